@@ -0,0 +1,248 @@
+//! Benchmarks of the engines: `KvStore`, the log-structured store, against
+//! `MemStore`, the in-memory store of Project1 ported to `KvsEngine`.
+//!
+//! Run them with `cargo bench`, or `cargo bench -- <FILTER>` for some of them,
+//! e.g. `cargo bench -- read/`. The open and compaction benchmarks only apply to
+//! `KvStore`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvsEngine, MemStore, Options};
+use std::fs;
+use tempfile::TempDir;
+
+/// The number of keys written, or read, by an iteration.
+const OPS: usize = 1000;
+
+/// The number of keys in a store the keys are read from.
+const KEYS: usize = 10_000;
+
+/// The length of the values.
+const VALUE_LEN: usize = 100;
+
+/// How many of the keys are read over and over by the hot key benchmarks.
+const HOT_KEYS: usize = 16;
+
+/// The engines compared.
+#[derive(Clone, Copy)]
+enum Engine {
+    Kvs,
+    Mem,
+}
+
+impl Engine {
+    const ALL: [Engine; 2] = [Engine::Kvs, Engine::Mem];
+
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Mem => "mem",
+        }
+    }
+}
+
+/// An engine behind the same interface, so every benchmark runs the same code
+/// on both of them.
+enum Store {
+    Kvs(KvStore),
+    Mem(MemStore),
+}
+
+impl Store {
+    fn new(engine: Engine, dir: &TempDir) -> Store {
+        match engine {
+            Engine::Kvs => Store::Kvs(KvStore::open(dir.path()).unwrap()),
+            Engine::Mem => Store::Mem(MemStore::new()),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) {
+        match self {
+            Store::Kvs(store) => store.set_bytes(key, value).unwrap(),
+            Store::Mem(store) => store.set_bytes(key, value).unwrap(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Store::Kvs(store) => store.get_bytes(key).unwrap(),
+            Store::Mem(store) => store.get_bytes(key).unwrap(),
+        }
+    }
+}
+
+/// A xorshift generator, so the random keys are the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        Rng(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:08}", i).into_bytes()
+}
+
+fn value(i: usize) -> Vec<u8> {
+    let mut value = format!("value{}", i).into_bytes();
+    value.resize(VALUE_LEN, b'.');
+    value
+}
+
+/// A store holding the keys `0..keys`, in its own directory.
+fn filled(engine: Engine, keys: usize) -> (TempDir, Store) {
+    let dir = TempDir::new().unwrap();
+    let store = Store::new(engine, &dir);
+    for i in 0..keys {
+        store.set(key(i), value(i));
+    }
+    (dir, store)
+}
+
+/// Writing new keys in order, or in a random order, to an empty store. The
+/// store is closed and deleted outside of the measurement.
+fn write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Elements(OPS as u64));
+    for engine in Engine::ALL {
+        group.bench_function(BenchmarkId::new("sequential", engine.name()), |b| {
+            b.iter_batched(
+                || filled(engine, 0),
+                |(dir, store)| {
+                    for i in 0..OPS {
+                        store.set(key(i), value(i));
+                    }
+                    (dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        group.bench_function(BenchmarkId::new("random", engine.name()), |b| {
+            b.iter_batched(
+                || (filled(engine, 0), Rng::new()),
+                |((dir, store), mut rng)| {
+                    for _ in 0..OPS {
+                        let i = rng.below(KEYS);
+                        store.set(key(i), value(i));
+                    }
+                    (dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Reading the same few keys over and over, which stay in the page cache, or
+/// keys picked at random from the whole store.
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Elements(OPS as u64));
+    for engine in Engine::ALL {
+        let (_dir, store) = filled(engine, KEYS);
+        group.bench_function(BenchmarkId::new("hot", engine.name()), |b| {
+            b.iter(|| {
+                for i in 0..OPS {
+                    assert!(store.get(&key(i % HOT_KEYS)).is_some());
+                }
+            })
+        });
+        let mut rng = Rng::new();
+        group.bench_function(BenchmarkId::new("cold", engine.name()), |b| {
+            b.iter(|| {
+                for _ in 0..OPS {
+                    assert!(store.get(&key(rng.below(KEYS))).is_some());
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("missing", engine.name()), |b| {
+            b.iter(|| {
+                for i in 0..OPS {
+                    assert!(store.get(&key(KEYS + i)).is_none());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Opening a `KvStore`, either replaying the whole log into the index, or
+/// loading the index from the hint file. The log either holds only live
+/// records, or each key is overwritten 4 times.
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(20);
+    for &(records, overwrites) in &[(KEYS, 1), (10 * KEYS, 1), (KEYS, 4)] {
+        let dir = TempDir::new().unwrap();
+        let mut options = Options::new();
+        options.compaction_threshold(u64::MAX);
+        let store = KvStore::open_with(dir.path(), &options).unwrap();
+        for round in 0..overwrites {
+            for i in 0..records {
+                store.set_bytes(key(i), value(i + round)).unwrap();
+            }
+        }
+        drop(store);
+
+        group.throughput(Throughput::Elements((records * overwrites) as u64));
+        let name = format!("{}x{}", records, overwrites);
+        // The store writes the hint file when it's closed, so it's removed
+        // before each replay. The stores are dropped outside of the timing.
+        group.bench_function(BenchmarkId::new("replay", &name), |b| {
+            b.iter_batched(
+                || fs::remove_file(dir.path().join("kvs.hint")).unwrap(),
+                |()| KvStore::open(dir.path()).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+        group.bench_function(BenchmarkId::new("hint", &name), |b| {
+            b.iter_batched(
+                || assert!(dir.path().join("kvs.hint").exists()),
+                |()| KvStore::open(dir.path()).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Compacting a `KvStore` where each key is written 4 times, so 3 quarters of
+/// the log is stale.
+fn compact(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+    group.bench_function("stale-3/4", |b| {
+        b.iter_batched(
+            || {
+                let dir = TempDir::new().unwrap();
+                let mut options = Options::new();
+                options.compaction_threshold(u64::MAX);
+                let store = KvStore::open_with(dir.path(), &options).unwrap();
+                for round in 0..4 {
+                    for i in 0..KEYS {
+                        store.set_bytes(key(i), value(i + round)).unwrap();
+                    }
+                }
+                (dir, store)
+            },
+            |(dir, store)| {
+                store.compact().unwrap();
+                (dir, store)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, write, read, open, compact);
+criterion_main!(benches);
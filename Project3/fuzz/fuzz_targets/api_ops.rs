@@ -0,0 +1,137 @@
+//! Apply arbitrary sequences of operations to a store, and check it against a
+//! `BTreeMap` after every one of them. Nothing may panic, and the store must
+//! hold the same keys as the map, also after it's compacted or reopened.
+//!
+//! Run it with `cargo +nightly fuzz run api_ops`.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use kvs::{KvStore, KvsEngine, KvsError, Options, WriteBatch};
+use libfuzzer_sys::fuzz_target;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+const MAX_KEY_SIZE: usize = 64;
+const MAX_VALUE_SIZE: usize = 256;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Get(Vec<u8>),
+    Remove(Vec<u8>),
+    Batch(Vec<(Vec<u8>, Option<Vec<u8>>)>),
+    // The TTLs are hours long, so nothing expires during a run.
+    SetWithTtl(Vec<u8>, Vec<u8>, u8),
+    // A TTL of 0 expires the key at once.
+    Expire(Vec<u8>, u8),
+    Scan(Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ScanPrefix(Vec<u8>),
+    Compact,
+    Reopen,
+}
+
+fn hours(n: u8) -> Duration {
+    Duration::from_secs(3600 * u64::from(n))
+}
+
+fn too_large(key: &[u8], value: &[u8]) -> bool {
+    key.len() > MAX_KEY_SIZE || value.len() > MAX_VALUE_SIZE
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut options = Options::new();
+    options
+        .segment_size(256)
+        .compaction_threshold(512)
+        .max_key_size(MAX_KEY_SIZE)
+        .max_value_size(MAX_VALUE_SIZE);
+    let open = || KvStore::open_with(dir.path(), &options).unwrap();
+    let mut store = open();
+    let mut model = BTreeMap::new();
+
+    for op in ops {
+        match op {
+            Op::Set(key, value) => {
+                let result = store.set_bytes(key.clone(), value.clone());
+                assert_eq!(result.is_err(), too_large(&key, &value));
+                if result.is_ok() {
+                    model.insert(key, value);
+                }
+            }
+            Op::Get(key) => assert_eq!(store.get_bytes(&key).unwrap(), model.get(&key).cloned()),
+            Op::Remove(key) => match store.remove_bytes(&key) {
+                Ok(()) => assert!(model.remove(&key).is_some()),
+                Err(KvsError::KeyNotFound) => assert!(!model.contains_key(&key)),
+                Err(err) => panic!("remove failed: {}", err),
+            },
+            Op::Batch(ops) => {
+                let mut batch = WriteBatch::new();
+                for (key, value) in &ops {
+                    match value {
+                        Some(value) => batch.set_bytes(key.clone(), value.clone()),
+                        None => batch.remove_bytes(key.clone()),
+                    };
+                }
+                let result = store.write(batch);
+                let invalid = ops
+                    .iter()
+                    .any(|(key, value)| value.as_ref().is_some_and(|value| too_large(key, value)));
+                assert_eq!(result.is_err(), invalid);
+                if result.is_ok() {
+                    for (key, value) in ops {
+                        match value {
+                            Some(value) => model.insert(key, value),
+                            None => model.remove(&key),
+                        };
+                    }
+                }
+            }
+            Op::SetWithTtl(key, value, ttl) => {
+                let result = store.set_bytes_with_ttl(key.clone(), value.clone(), hours(ttl.max(1)));
+                assert_eq!(result.is_err(), too_large(&key, &value));
+                if result.is_ok() {
+                    model.insert(key, value);
+                }
+            }
+            Op::Expire(key, ttl) => match store.expire_bytes(&key, hours(ttl)) {
+                Ok(()) if ttl == 0 => assert!(model.remove(&key).is_some()),
+                Ok(()) => assert!(model.contains_key(&key)),
+                Err(KvsError::KeyNotFound) => assert!(!model.contains_key(&key)),
+                Err(err) => panic!("expire failed: {}", err),
+            },
+            Op::Scan(start, end) => {
+                let pairs: Vec<_> = store
+                    .scan_bytes((start.clone(), end.clone()))
+                    .map(Result::unwrap)
+                    .collect();
+                let expected: Vec<_> = model
+                    .iter()
+                    .filter(|(key, _)| (start.as_ref(), end.as_ref()).contains(key))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                assert_eq!(pairs, expected);
+            }
+            Op::ScanPrefix(prefix) => {
+                let keys: Vec<_> = store.scan_prefix_bytes(prefix.clone()).keys().collect();
+                let expected: Vec<_> = model
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                assert_eq!(keys, expected);
+            }
+            Op::Compact => store.compact().unwrap(),
+            Op::Reopen => {
+                drop(store);
+                store = open();
+            }
+        }
+    }
+
+    let pairs: Vec<_> = store.scan_bytes(..).map(Result::unwrap).collect();
+    let expected: Vec<_> = model.into_iter().collect();
+    assert_eq!(pairs, expected);
+});
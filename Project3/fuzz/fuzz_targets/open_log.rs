@@ -0,0 +1,51 @@
+//! Open a store from a log holding arbitrary bytes: a segment, a segment after
+//! a valid file header, or a `kvs.db` of the old format.
+//!
+//! Opening must either fail with an error, or succeed and then read every key,
+//! write, compact and reopen the store, all without panicking. Run it with
+//! `cargo +nightly fuzz run open_log -- -malloc_limit_mb=64`, so a bogus
+//! length which gets allocated is reported too.
+
+#![no_main]
+
+use kvs::{KvStore, KvsEngine};
+use libfuzzer_sys::fuzz_target;
+use std::fs;
+
+/// The file header of the current format version.
+const HEADER: &[u8] = b"KVS\0\x03\0\0\0";
+
+fuzz_target!(|data: &[u8]| {
+    let (kind, log) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let dir = tempfile::TempDir::new().unwrap();
+    match kind % 3 {
+        0 => fs::write(dir.path().join("1.log"), log),
+        1 => fs::write(dir.path().join("1.log"), [HEADER, log].concat()),
+        _ => fs::write(dir.path().join("kvs.db"), log),
+    }
+    .unwrap();
+
+    let store = match KvStore::open(dir.path()) {
+        Ok(store) => store,
+        Err(_) => return,
+    };
+    let keys: Vec<Vec<u8>> = store.scan_bytes(..).keys().collect();
+    for key in &keys {
+        let _ = store.get_bytes(key);
+    }
+    for pair in store.scan_bytes(..) {
+        let _ = pair;
+    }
+    if store.set_bytes(b"key".to_vec(), b"value".to_vec()).is_err() {
+        return;
+    }
+    let _ = store.compact();
+    drop(store);
+
+    // Whatever was read, the store is written by this crate now.
+    let store = KvStore::open(dir.path()).expect("cannot reopen the store");
+    assert_eq!(store.get_bytes(b"key").unwrap(), Some(b"value".to_vec()));
+});
@@ -0,0 +1,154 @@
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{KvStore, KvsEngine, KvsError, Result, WriteBatch};
+use futures_channel::oneshot;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+/// An async facade of a `KvStore`, for the services running on an async
+/// executor such as tokio. The file I/O and the compactions block, so they're
+/// run on a dedicated pool of threads, and the methods return futures of their
+/// results.
+///
+/// An operation starts when the method is called, not when the future is
+/// first polled, and it runs to the end even if the future is dropped. So
+/// dropping a future, e.g. on a timeout or in a `select!` branch which lost,
+/// never leaves the store in between: the operation is applied just like an
+/// awaited one, only its result is lost. The operations are applied in the
+/// order they're called if the pool has a single thread; otherwise only the
+/// awaited ones are ordered.
+///
+/// An `AsyncKvStore` is a handle which can be cloned, all the clones share the
+/// pool. Dropping the last one waits for the operations already called.
+///
+/// # Example
+///
+/// ```
+/// use kvs::{AsyncKvStore, KvStore};
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let store = AsyncKvStore::new(KvStore::open(dir.path()).unwrap());
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.block_on(async {
+///     store.set("key".to_owned(), "value".to_owned()).await.unwrap();
+///     assert_eq!(Some("value".to_owned()), store.get("key".to_owned()).await.unwrap());
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncKvStore {
+    store: KvStore,
+    pool: Arc<SharedQueueThreadPool>,
+}
+
+/// The future of an operation of `AsyncKvStore`.
+#[derive(Debug)]
+#[must_use = "the operation runs anyway, but its result is lost unless the future is awaited"]
+pub struct KvsFuture<T> {
+    result: oneshot::Receiver<Result<T>>,
+}
+
+impl AsyncKvStore {
+    /// Wrap a store, with a thread per CPU doing its disk work.
+    pub fn new(store: KvStore) -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        AsyncKvStore::with_threads(store, threads)
+    }
+
+    /// Wrap a store, with `threads` threads doing its disk work.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is 0.
+    pub fn with_threads(store: KvStore, threads: usize) -> Self {
+        assert!(threads > 0, "AsyncKvStore needs at least one thread");
+        let pool = SharedQueueThreadPool::new(threads as u32).expect("Cannot spawn the threads");
+        AsyncKvStore {
+            store,
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// The wrapped store, for the blocking calls.
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    /// Store a key with its value. See `KvsEngine::set`.
+    pub fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        self.spawn(move |store| store.set(key, value))
+    }
+
+    /// Get a key's value. See `KvsEngine::get`.
+    pub fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        self.spawn(move |store| store.get(key))
+    }
+
+    /// Remove a key's value. See `KvsEngine::remove`.
+    pub fn remove(&self, key: String) -> KvsFuture<()> {
+        self.spawn(move |store| store.remove(key))
+    }
+
+    /// Store a key with its value as raw bytes. See `KvsEngine::set_bytes`.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> KvsFuture<()> {
+        self.spawn(move |store| store.set_bytes(key, value))
+    }
+
+    /// Get a key's value as raw bytes. See `KvsEngine::get_bytes`.
+    pub fn get_bytes(&self, key: Vec<u8>) -> KvsFuture<Option<Vec<u8>>> {
+        self.spawn(move |store| store.get_bytes(&key))
+    }
+
+    /// Remove a key given as raw bytes. See `KvsEngine::remove_bytes`.
+    pub fn remove_bytes(&self, key: Vec<u8>) -> KvsFuture<()> {
+        self.spawn(move |store| store.remove_bytes(&key))
+    }
+
+    /// Store a key with its value, which expires after `ttl`. See
+    /// `KvStore::set_with_ttl`.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> KvsFuture<()> {
+        self.spawn(move |store| store.set_with_ttl(key, value, ttl))
+    }
+
+    /// Apply all the operations of a batch at once. See `KvStore::write`.
+    pub fn write(&self, batch: WriteBatch) -> KvsFuture<()> {
+        self.spawn(move |store| store.write(batch))
+    }
+
+    /// Compact the log, and wait for it. See `KvStore::compact`.
+    pub fn compact(&self) -> KvsFuture<()> {
+        self.spawn(KvStore::compact)
+    }
+
+    /// Run an operation on the pool, and return the future of its result.
+    fn spawn<T, F>(&self, operation: F) -> KvsFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&KvStore) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let store = self.store.clone();
+        self.pool.spawn(move || {
+            // Nobody waits for the result if the future is dropped.
+            let _ = sender.send(operation(&store));
+        });
+        KvsFuture { result: receiver }
+    }
+}
+
+impl<T> Future for KvsFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.result).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            // The operation panicked.
+            Poll::Ready(Err(oneshot::Canceled)) => Poll::Ready(Err(KvsError::Io(
+                io::Error::other("the operation panicked"),
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! What the binaries share.
+
+use kvs::{KvStore, Options, Result};
+
+/// Open the store in the current directory, and report the torn log tail
+/// discarded by it, if any.
+pub fn open_store(options: &Options) -> Result<KvStore> {
+    let store = KvStore::open_with("./", options)?;
+    if store.discarded_bytes() > 0 {
+        eprintln!(
+            "Discarded {} bytes of a torn log tail",
+            store.discarded_bytes()
+        );
+    }
+    Ok(store)
+}
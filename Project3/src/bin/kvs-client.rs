@@ -0,0 +1,83 @@
+use kvs::{KvsClient, KvsError, Result, DEFAULT_ADDR};
+use std::net::SocketAddr;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(name = "set")]
+    /// Set the value of a string key to a string
+    Set {
+        #[structopt(required = true)]
+        /// A string key
+        key: String,
+        #[structopt(required = true)]
+        /// The string value of the key
+        value: String,
+        #[structopt(
+            long = "addr",
+            value_name = "IP:PORT",
+            raw(default_value = "DEFAULT_ADDR")
+        )]
+        /// The address of the server
+        addr: SocketAddr,
+    },
+    #[structopt(name = "get")]
+    /// Get the string value of a given string key
+    Get {
+        #[structopt(required = true)]
+        /// A string key
+        key: String,
+        #[structopt(
+            long = "addr",
+            value_name = "IP:PORT",
+            raw(default_value = "DEFAULT_ADDR")
+        )]
+        /// The address of the server
+        addr: SocketAddr,
+    },
+    #[structopt(name = "rm")]
+    /// Remove the value of a given string key
+    Remove {
+        #[structopt(required = true)]
+        /// The key to be removed
+        key: String,
+        #[structopt(
+            long = "addr",
+            value_name = "IP:PORT",
+            raw(default_value = "DEFAULT_ADDR")
+        )]
+        /// The address of the server
+        addr: SocketAddr,
+    },
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "kvs-client")]
+#[structopt(raw(setting = "structopt::clap::AppSettings::DisableHelpSubcommand"))]
+#[structopt(raw(setting = "structopt::clap::AppSettings::SubcommandRequiredElseHelp"))]
+#[structopt(raw(setting = "structopt::clap::AppSettings::VersionlessSubcommands"))]
+struct Opt {
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    match opt.cmd {
+        Command::Set { key, value, addr } => KvsClient::connect(addr)?.set(key, value),
+        Command::Get { key, addr } => {
+            let value = KvsClient::connect(addr)?
+                .get(key)?
+                .unwrap_or_else(|| String::from("Key not found"));
+            println!("{}", value);
+            Ok(())
+        }
+        Command::Remove { key, addr } => match KvsClient::connect(addr)?.remove(key) {
+            Err(KvsError::KeyNotFound) => {
+                println!("{}", KvsError::KeyNotFound);
+                std::process::exit(1);
+            }
+            result => result,
+        },
+    }
+}
@@ -0,0 +1,105 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsServer, Options, RespServer, Result, DEFAULT_ADDR};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+mod common;
+
+#[derive(StructOpt)]
+#[structopt(name = "kvs-server")]
+/// Serve the store in the current directory over TCP
+struct Opt {
+    #[structopt(
+        long = "addr",
+        value_name = "IP:PORT",
+        raw(default_value = "DEFAULT_ADDR")
+    )]
+    /// The address to listen on
+    addr: SocketAddr,
+    #[structopt(
+        long = "protocol",
+        default_value = "kvs",
+        raw(possible_values = r#"&["kvs", "resp"]"#)
+    )]
+    /// The protocol to speak: the one of `kvs-client`, or RESP for the Redis
+    /// clients
+    protocol: Protocol,
+    #[structopt(
+        long = "pool",
+        default_value = "naive",
+        raw(possible_values = r#"&["naive", "shared-queue", "rayon"]"#)
+    )]
+    /// The thread pool serving the connections: a thread per connection, a
+    /// fixed number of threads, or rayon. A connection holds a thread until
+    /// it's closed, so the fixed pools serve at most `--threads` clients at
+    /// once, and the others wait
+    pool: Pool,
+    #[structopt(long = "threads", value_name = "N")]
+    /// The number of threads of the pool, one per CPU by default
+    threads: Option<u32>,
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Kvs,
+    Resp,
+}
+
+#[derive(Clone, Copy)]
+enum Pool {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!("Invalid protocol {}", s)),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let store = common::open_store(&Options::new())?;
+    eprintln!(
+        "kvs-server {} listening on {}",
+        env!("CARGO_PKG_VERSION"),
+        opt.addr
+    );
+    match opt.pool {
+        Pool::Naive => serve::<NaiveThreadPool>(store, &opt),
+        Pool::SharedQueue => serve::<SharedQueueThreadPool>(store, &opt),
+        Pool::Rayon => serve::<RayonThreadPool>(store, &opt),
+    }
+}
+
+fn serve<P: ThreadPool>(store: KvStore, opt: &Opt) -> Result<()> {
+    let threads = opt.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |threads| threads.get() as u32)
+    });
+    let pool = P::new(threads)?;
+    match opt.protocol {
+        Protocol::Kvs => KvsServer::new(store, pool).run(opt.addr),
+        Protocol::Resp => RespServer::new(store, pool).run(opt.addr),
+    }
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(Pool::Naive),
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "rayon" => Ok(Pool::Rayon),
+            _ => Err(format!("Invalid pool {}", s)),
+        }
+    }
+}
@@ -1,6 +1,19 @@
-use kvs::{KvStore, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use kvs::{KvStore, KvsEngine, KvsError, Options, Result, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
+mod common;
+
 #[derive(StructOpt)]
 enum Command {
     #[structopt(name = "set")]
@@ -12,6 +25,10 @@ enum Command {
         #[structopt(required = true)]
         /// The string value of the key
         value: String,
+        #[structopt(long = "ttl", parse(try_from_str = "parse_ttl"))]
+        /// Expire the key after this long: a number of seconds, or a number
+        /// followed by `ms`, `s`, `m`, `h` or `d`
+        ttl: Option<Duration>,
     },
     #[structopt(name = "get")]
     /// Get the string value of a given string key
@@ -27,6 +44,78 @@ enum Command {
         /// The key to be removed
         key: String,
     },
+    #[structopt(name = "scan")]
+    /// Print the keys and their values in order, one `<KEY> <VALUE>` per line
+    Scan {
+        #[structopt(long = "prefix", raw(conflicts_with_all = r#"&["from", "to"]"#))]
+        /// Only the keys starting with the prefix
+        prefix: Option<String>,
+        #[structopt(long = "from")]
+        /// Only the keys from this one on
+        from: Option<String>,
+        #[structopt(long = "to")]
+        /// Only the keys before this one
+        to: Option<String>,
+    },
+    #[structopt(name = "keys")]
+    /// Print the keys in order, one per line
+    Keys {
+        /// Only the keys starting with the prefix
+        prefix: Option<String>,
+    },
+    #[structopt(name = "batch")]
+    /// Apply the operations in a file all together, one per line: `set <KEY> <VALUE>`
+    /// or `rm <KEY>`. Empty lines and lines starting with `#` are skipped
+    Batch {
+        #[structopt(required = true, parse(from_os_str))]
+        /// The file of operations, or `-` for the standard input
+        file: PathBuf,
+    },
+    #[structopt(name = "export")]
+    /// Print every key and its value, as JSON lines of `{"key": ..., "value": ...}`
+    /// or as CSV with a `key,value` header
+    Export {
+        #[structopt(
+            long = "format",
+            default_value = "jsonl",
+            raw(possible_values = r#"&["jsonl", "csv"]"#)
+        )]
+        /// The format of the output
+        format: DumpFormat,
+    },
+    #[structopt(name = "import")]
+    /// Set the keys in a file written by `export`, in batches of up to 64 MiB,
+    /// and compact the store once at the end
+    Import {
+        #[structopt(
+            long = "format",
+            default_value = "jsonl",
+            raw(possible_values = r#"&["jsonl", "csv"]"#)
+        )]
+        /// The format of the file
+        format: DumpFormat,
+        #[structopt(required = true, parse(from_os_str))]
+        /// The file to import, or `-` for the standard input
+        file: PathBuf,
+    },
+    #[structopt(name = "backup")]
+    /// Write a consistent copy of the store into a new directory, or into a
+    /// tarball if the path ends with `.tar`, `.tar.gz` or `.tgz`, and check the
+    /// copy holds as many keys as the store. The store is opened read only, so
+    /// it's backed up while `kvs-server` serves it
+    Backup {
+        #[structopt(required = true, parse(from_os_str))]
+        /// The directory or the tarball to write
+        dest: PathBuf,
+    },
+    #[structopt(name = "restore")]
+    /// Restore a backup into the current directory, which must not hold a
+    /// store, and check it holds as many keys as the backup
+    Restore {
+        #[structopt(required = true, parse(from_os_str))]
+        /// The directory or the tarball written by `backup`
+        backup: PathBuf,
+    },
 }
 
 #[derive(StructOpt)]
@@ -34,23 +123,508 @@ enum Command {
 #[structopt(raw(setting = "structopt::clap::AppSettings::SubcommandRequiredElseHelp"))]
 #[structopt(raw(setting = "structopt::clap::AppSettings::VersionlessSubcommands"))]
 struct Opt {
+    #[structopt(
+        long = "input-format",
+        default_value = "text",
+        raw(global = "true", possible_values = r#"&["text", "hex", "base64"]"#)
+    )]
+    /// How the keys and values given on the command line, or in a batch file,
+    /// are encoded
+    input_format: Encoding,
+    #[structopt(
+        long = "output-format",
+        default_value = "text",
+        raw(global = "true", possible_values = r#"&["text", "hex", "base64"]"#)
+    )]
+    /// How the keys and values printed are encoded
+    output_format: Encoding,
+    #[structopt(long = "read-only", raw(global = "true"))]
+    /// Open the store without changing anything in the directory, next to a
+    /// process writing to it. The writes are rejected
+    read_only: bool,
     #[structopt(subcommand)]
     cmd: Command,
 }
 
+/// How the keys and values are written on the command line. The stored keys and
+/// values are raw bytes.
+#[derive(Clone, Copy)]
+enum Encoding {
+    Text,
+    Hex,
+    Base64,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Encoding::Text),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            _ => Err(format!("Invalid format {}", s)),
+        }
+    }
+}
+
+impl Encoding {
+    fn decode(self, input: &str) -> Result<Vec<u8>> {
+        let bytes = match self {
+            Encoding::Text => Ok(input.as_bytes().to_vec()),
+            Encoding::Hex => hex::decode(input).map_err(|_| "hex"),
+            Encoding::Base64 => base64::decode(input).map_err(|_| "base64"),
+        };
+        bytes.map_err(|format| KvsError::InvalidCommand {
+            command: format!("{} (not {})", input, format),
+        })
+    }
+
+    fn encode(self, bytes: Vec<u8>) -> Result<String> {
+        match self {
+            Encoding::Text => Ok(String::from_utf8(bytes)?),
+            Encoding::Hex => Ok(hex::encode(bytes)),
+            Encoding::Base64 => Ok(base64::encode(bytes)),
+        }
+    }
+}
+
+/// The format of the files written by `export` and read by `import`.
+#[derive(Clone, Copy)]
+enum DumpFormat {
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(DumpFormat::Jsonl),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(format!("Invalid format {}", s)),
+        }
+    }
+}
+
+/// A key and its value in an exported file, encoded with `--output-format`,
+/// or with `--input-format` when they're imported.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// How a backup is stored, told by the extension of its path.
+#[derive(Clone, Copy, PartialEq)]
+enum BackupFormat {
+    Dir,
+    Tar,
+    TarGz,
+}
+
+impl BackupFormat {
+    fn of(path: &Path) -> BackupFormat {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            BackupFormat::TarGz
+        } else if name.ends_with(".tar") {
+            BackupFormat::Tar
+        } else {
+            BackupFormat::Dir
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let mut store = KvStore::open("./")?;
+    if let Command::Restore { backup } = &opt.cmd {
+        // The store is created by the restore, not by opening it.
+        if opt.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        return restore(backup, Path::new("./"));
+    }
+    let mut options = Options::new();
+    options.read_only(opt.read_only);
+    match opt.cmd {
+        // The import compacts once, at the end.
+        Command::Import { .. } => {
+            options.compaction_threshold(u64::MAX);
+        }
+        // The backup reads the store next to its writer, e.g. `kvs-server`.
+        Command::Backup { .. } => {
+            options.read_only(true);
+        }
+        _ => (),
+    }
+    let store = common::open_store(&options)?;
 
+    let (input, output) = (opt.input_format, opt.output_format);
     match opt.cmd {
-        Command::Set { key, value } => store.set(key, value),
+        Command::Set { key, value, ttl } => {
+            let (key, value) = (input.decode(&key)?, input.decode(&value)?);
+            match ttl {
+                Some(ttl) => store.set_bytes_with_ttl(key, value, ttl),
+                None => store.set_bytes(key, value),
+            }
+        }
         Command::Get { key } => {
-            let value = store
-                .get(key)?
-                .or_else(|| Some(String::from("Key not found")));
-            println!("{}", value.unwrap());
+            let value = match store.get_bytes(&input.decode(&key)?)? {
+                Some(value) => output.encode(value)?,
+                None => String::from("Key not found"),
+            };
+            println!("{}", value);
+            Ok(())
+        }
+        Command::Remove { key } => match store.remove_bytes(&input.decode(&key)?) {
+            Err(KvsError::KeyNotFound) => {
+                println!("{}", KvsError::KeyNotFound);
+                std::process::exit(1);
+            }
+            result => result,
+        },
+        Command::Scan { prefix, from, to } => {
+            let scan = match prefix {
+                Some(prefix) => store.scan_prefix_bytes(input.decode(&prefix)?),
+                None => store.scan_bytes((
+                    bound(input, from, Bound::Included)?,
+                    bound(input, to, Bound::Excluded)?,
+                )),
+            };
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for pair in scan {
+                let (key, value) = pair?;
+                writeln!(stdout, "{} {}", output.encode(key)?, output.encode(value)?)?;
+            }
+            Ok(())
+        }
+        Command::Keys { prefix } => {
+            let prefix = match prefix {
+                Some(prefix) => input.decode(&prefix)?,
+                None => Vec::new(),
+            };
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for key in store.scan_prefix_bytes(prefix).keys() {
+                writeln!(stdout, "{}", output.encode(key)?)?;
+            }
             Ok(())
         }
-        Command::Remove { key } => store.remove(key),
+        Command::Batch { file } => {
+            let batch = if file.as_os_str() == "-" {
+                read_batch(io::stdin().lock(), input)?
+            } else {
+                read_batch(BufReader::new(File::open(file)?), input)?
+            };
+            store.write(batch)
+        }
+        Command::Export { format } => export(&store, format, output, io::stdout().lock()),
+        Command::Import { format, file } => {
+            if file.as_os_str() == "-" {
+                import(&store, format, input, io::stdin().lock())
+            } else {
+                import(&store, format, input, BufReader::new(File::open(file)?))
+            }
+        }
+        Command::Backup { dest } => backup(&store, &dest),
+        Command::Restore { .. } => unreachable!(),
+    }
+}
+
+/// Write every key and its value.
+fn export(store: &KvStore, format: DumpFormat, output: Encoding, writer: impl Write) -> Result<()> {
+    let records = store.scan_bytes(..).map(|pair| -> Result<Record> {
+        let (key, value) = pair?;
+        Ok(Record {
+            key: output.encode(key)?,
+            value: output.encode(value)?,
+        })
+    });
+    match format {
+        DumpFormat::Jsonl => {
+            let mut writer = io::BufWriter::new(writer);
+            for record in records {
+                serde_json::to_writer(&mut writer, &record?)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        DumpFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for record in records {
+                writer.serialize(record?).map_err(io::Error::from)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// The keys and values `import` writes in a batch, well under the 4 GiB limit
+/// of a record.
+const IMPORT_BATCH_BYTES: usize = 64 * 1024 * 1024;
+
+/// Set the keys in an exported file, in batches of up to `IMPORT_BATCH_BYTES`
+/// of keys and values, and compact the store once at the end.
+///
+/// A batch is written as a single record, so a file of any size is imported,
+/// but only a file that fits in one batch is imported all or nothing: an
+/// invalid record stops the import after the batches already written.
+fn import(store: &KvStore, format: DumpFormat, input: Encoding, reader: impl Read) -> Result<()> {
+    let records: Box<dyn Iterator<Item = Result<Record>>> = match format {
+        DumpFormat::Jsonl => Box::new(
+            serde_json::Deserializer::from_reader(reader)
+                .into_iter()
+                .map(|record| Ok(record?)),
+        ),
+        DumpFormat::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize()
+                .map(|record| Ok(record.map_err(io::Error::from)?)),
+        ),
+    };
+    let (mut batch, mut batch_bytes) = (WriteBatch::new(), 0);
+    for record in records {
+        let record = record?;
+        let (key, value) = (input.decode(&record.key)?, input.decode(&record.value)?);
+        batch_bytes += key.len() + value.len();
+        batch.set_bytes(key, value);
+        if batch_bytes >= IMPORT_BATCH_BYTES {
+            store.write(mem::take(&mut batch))?;
+            batch_bytes = 0;
+        }
+    }
+    store.write(batch)?;
+    store.compact()
+}
+
+/// Write a checkpoint of the store into `dest`, and check it by opening it.
+/// A tarball is checked by unpacking it next to itself.
+///
+/// The backup is written next to `dest` and renamed onto it once it's checked,
+/// so a failed backup leaves nothing behind and it's retried as it is.
+fn backup(store: &KvStore, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        )));
+    }
+    // The store is opened read only, so the checkpoint holds the keys it
+    // loaded. The expired keys are counted too, some may expire meanwhile.
+    let expected = store.indexed_keys();
+    let staging = with_suffix(dest, ".tmp");
+    // It's not created if it fails, so it's never someone else's below.
+    store.checkpoint(&staging)?;
+    let mut tarball = None;
+    let result = write_backup(&staging, dest, expected, &mut tarball);
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&staging);
+        if let Some(tarball) = tarball {
+            let _ = fs::remove_file(tarball);
+        }
+    }
+    result?;
+    println!("Backed up {} keys", expected);
+    Ok(())
+}
+
+/// Check the checkpoint in `staging`, or pack it into a tarball and check that,
+/// and rename it onto `dest`. The tarball being written is set to `tarball`
+/// once it's created.
+fn write_backup(
+    staging: &Path,
+    dest: &Path,
+    expected: usize,
+    tarball: &mut Option<PathBuf>,
+) -> Result<()> {
+    let format = BackupFormat::of(dest);
+    let checked = match format {
+        BackupFormat::Dir => {
+            check_count(expected, KvStore::open_read_only(staging)?.indexed_keys())?;
+            staging.to_path_buf()
+        }
+        _ => {
+            let path = with_suffix(dest, ".part");
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            *tarball = Some(path.clone());
+            write_tarball(staging, file, format)?;
+            // The tarball is checked, rather than what it's packed from.
+            fs::remove_dir_all(staging)?;
+            fs::create_dir(staging)?;
+            unpack(&path, staging, format)?;
+            let found = KvStore::open_read_only(staging)?.indexed_keys();
+            fs::remove_dir_all(staging)?;
+            check_count(expected, found)?;
+            path
+        }
+    };
+    fs::rename(checked, dest)?;
+    Ok(())
+}
+
+/// Restore a backup into the store directory `dest`. It's copied, or unpacked,
+/// into a directory in `dest` and checked by opening it first, so `dest` is
+/// left alone if it fails.
+fn restore(backup: &Path, dest: &Path) -> Result<()> {
+    match KvStore::open_with(dest, Options::new().read_only(true)) {
+        Err(KvsError::StoreNotFound) => (),
+        Ok(_) => return Err(KvsError::StoreExists),
+        Err(err) => return Err(err),
+    }
+    // It's only removed once it's created here, it may belong to someone else.
+    let staging = dest.join("restore.tmp");
+    fs::create_dir(&staging)?;
+    let count = match stage_restore(backup, &staging) {
+        Ok(count) => count,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(err);
+        }
+    };
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dest.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&staging)?;
+    println!("Restored {} keys", count);
+    Ok(())
+}
+
+/// Copy, or unpack, a backup into the empty directory `staging`, and check it
+/// holds as many keys as the backup. Returns the number of keys.
+fn stage_restore(backup: &Path, staging: &Path) -> Result<usize> {
+    let format = BackupFormat::of(backup);
+    match format {
+        BackupFormat::Dir => {
+            for entry in fs::read_dir(backup)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    fs::copy(entry.path(), staging.join(entry.file_name()))?;
+                }
+            }
+        }
+        _ => unpack(backup, staging, format)?,
+    }
+    let source = match format {
+        BackupFormat::Dir => backup,
+        _ => staging,
+    };
+    let expected = KvStore::open_read_only(source)?.indexed_keys();
+    let found = KvStore::open(staging)?.indexed_keys();
+    check_count(expected, found)?;
+    Ok(found)
+}
+
+/// Pack the files of the directory `dir` into the tarball `file`.
+fn write_tarball(dir: &Path, file: File, format: BackupFormat) -> Result<()> {
+    let file = match format {
+        BackupFormat::TarGz => pack(dir, GzEncoder::new(file, Compression::default()))?.finish()?,
+        _ => pack(dir, file)?,
+    };
+    file.sync_all()?;
+    Ok(())
+}
+
+fn pack<W: Write>(dir: &Path, writer: W) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        builder.append_path_with_name(entry.path(), entry.file_name())?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Unpack the tarball `tarball` into the empty directory `dir`.
+fn unpack(tarball: &Path, dir: &Path, format: BackupFormat) -> Result<()> {
+    let file = File::open(tarball)?;
+    let reader: Box<dyn Read> = match format {
+        BackupFormat::TarGz => Box::new(GzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    tar::Archive::new(reader).unpack(dir)?;
+    Ok(())
+}
+
+/// The path a backup is written to before it's renamed onto `dest`.
+fn with_suffix(dest: &Path, suffix: &str) -> PathBuf {
+    let mut path = dest.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn check_count(expected: usize, found: usize) -> Result<()> {
+    if expected != found {
+        return Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the copy holds {} keys instead of {}", found, expected),
+        )));
+    }
+    Ok(())
+}
+
+fn parse_ttl(ttl: &str) -> std::result::Result<Duration, String> {
+    let split = ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len());
+    let (number, unit) = ttl.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid TTL {}", ttl))?;
+    let secs = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Invalid TTL unit {}", unit)),
+    };
+    number
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("TTL {} is too long", ttl))
+}
+
+fn bound(
+    input: Encoding,
+    key: Option<String>,
+    bound: fn(Vec<u8>) -> Bound<Vec<u8>>,
+) -> Result<Bound<Vec<u8>>> {
+    match key {
+        Some(key) => Ok(bound(input.decode(&key)?)),
+        None => Ok(Bound::Unbounded),
+    }
+}
+
+/// Parse the operations of a batch file. The value of a `set` is the rest of
+/// the line, so it may hold spaces.
+fn read_batch(reader: impl BufRead, input: Encoding) -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.splitn(3, ' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("set"), Some(key), Some(value)) if !key.is_empty() => {
+                batch.set_bytes(input.decode(key)?, input.decode(value)?);
+            }
+            (Some("rm"), Some(key), None) if !key.is_empty() => {
+                batch.remove_bytes(input.decode(key)?);
+            }
+            _ => {
+                return Err(KvsError::InvalidCommand {
+                    command: line.to_owned(),
+                })
+            }
+        }
     }
+    Ok(batch)
 }
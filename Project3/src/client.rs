@@ -0,0 +1,95 @@
+use crate::protocol::{read_message, write_message, Request, Response};
+use crate::{KvsError, Result};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// A connection to a `KvsServer`.
+///
+/// # Example
+///
+/// ```no_run
+/// use kvs::KvsClient;
+/// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// assert_eq!(Some("value".to_owned()), client.get("key".to_owned()).unwrap());
+/// ```
+#[derive(Debug)]
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connect to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Store a key with its value on the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Get a key's value from the server.
+    /// Return Some(_) if the key exists, return None otherwise.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a key's value on the server.
+    /// Return `KvsError::KeyNotFound` if the key doesn't exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Store a key with its value as raw bytes on the server.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.request(&Request::Set { key, value })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Get a key's value as raw bytes from the server.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Remove a key given as raw bytes on the server.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.request(&Request::Remove { key })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Send a request and wait for its response.
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request)?;
+        match read_message(&mut self.reader)? {
+            Some(response) => Ok(response),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
+
+/// The error for a response which doesn't answer the request.
+fn unexpected(response: Response) -> KvsError {
+    match response {
+        Response::KeyNotFound => KvsError::KeyNotFound,
+        Response::Error(message) => KvsError::Server { message },
+        response => KvsError::Server {
+            message: format!("Unexpected response {:?}", response),
+        },
+    }
+}
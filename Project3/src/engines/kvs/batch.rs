@@ -0,0 +1,58 @@
+use super::format::Operation;
+
+/// Sets and removals applied together by `KvStore::write`, in the order they
+/// were added.
+///
+/// # Example
+///
+/// ```
+/// use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("key1".to_owned(), "value1".to_owned())
+///     .remove("key2".to_owned());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub(super) ops: Vec<Operation>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Set a key to a value.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove a key, if it exists.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Set a key to a value, both raw bytes.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(Operation::Set { key, value });
+        self
+    }
+
+    /// Remove a key given as raw bytes, if it exists.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(Operation::Rm { key });
+        self
+    }
+
+    /// The number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether there's no operation in the batch.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
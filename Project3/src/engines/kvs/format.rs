@@ -0,0 +1,248 @@
+//! The on-disk format of the segments.
+//!
+//! Every segment starts with a file header: the magic number `KVS\0` and the
+//! format version as a little endian u32. It's followed by the records, each of
+//! them is the length of the payload and the CRC32 of the payload, both as
+//! little endian u32, followed by the payload which is an `Operation` encoded
+//! by bincode. The keys and values are raw bytes, which bincode encodes the
+//! same way as the strings of the older versions: a u64 length and the bytes.
+//!
+//! Version 2 added `Operation::Batch`, and version 3 added `Operation::SetEx`.
+//! The segments of the older versions are still read, but they're never
+//! appended to, so an older crate never finds a record it doesn't know.
+
+use crate::{KvsError, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cell::Cell;
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 4] = *b"KVS\0";
+
+/// The version of the format written by this crate.
+pub(super) const FORMAT_VERSION: u32 = 3;
+
+/// The length of the file header at the start of every segment.
+pub(super) const FILE_HEADER_LEN: u64 = 8;
+
+/// The length of the header before each record.
+pub(super) const RECORD_HEADER_LEN: u64 = 8;
+
+/// A enum used to represent the operations. This struct is directly write
+/// into log files, and deserialized directly.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Operation {
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Rm {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// The operations of a `WriteBatch`, which are applied all together.
+    Batch(#[serde(deserialize_with = "deserialize_batch")] Vec<Operation>),
+    /// Set a key which expires at `expires_at`, in milliseconds since the Unix
+    /// epoch.
+    SetEx {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+}
+
+/// Decode the operations of a batch, which are never batches themselves. A
+/// corrupt record may hold batches nested as deep as the record is long, which
+/// would overflow the stack.
+fn deserialize_batch<'de, D>(deserializer: D) -> std::result::Result<Vec<Operation>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    thread_local!(static IN_BATCH: Cell<bool> = const { Cell::new(false) });
+    if IN_BATCH.with(|in_batch| in_batch.replace(true)) {
+        return Err(D::Error::custom("nested batch"));
+    }
+    let ops = Vec::deserialize(deserializer);
+    IN_BATCH.with(|in_batch| in_batch.set(false));
+    ops
+}
+
+/// An operation in a `kvs.db` of the old single file format, which only held
+/// strings.
+#[derive(Deserialize)]
+enum LegacyOperation {
+    Set { key: String, value: String },
+    Get { key: String },
+    Rm { key: String },
+}
+
+impl From<LegacyOperation> for Operation {
+    fn from(op: LegacyOperation) -> Operation {
+        match op {
+            LegacyOperation::Set { key, value } => Operation::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+            },
+            LegacyOperation::Get { key } => Operation::Get {
+                key: key.into_bytes(),
+            },
+            LegacyOperation::Rm { key } => Operation::Rm {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+impl Operation {
+    /// The key the operation is on, None for a batch.
+    pub(super) fn key(&self) -> Option<&[u8]> {
+        match self {
+            Operation::Set { key, .. }
+            | Operation::SetEx { key, .. }
+            | Operation::Get { key }
+            | Operation::Rm { key } => Some(key),
+            Operation::Batch(_) => None,
+        }
+    }
+
+    /// The value the operation sets the key to.
+    pub(super) fn into_value(self) -> Option<Vec<u8>> {
+        match self {
+            Operation::Set { value, .. } | Operation::SetEx { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// When the key set by the operation expires.
+    pub(super) fn expires_at(&self) -> Option<u64> {
+        match self {
+            Operation::SetEx { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        }
+    }
+}
+
+/// Write the file header of a new segment.
+pub(super) fn write_file_header(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Check the file header of the segment `gen`, there are `file_len` bytes in the
+/// segment, and return the format version. Returns None if the segment is too
+/// short to hold a header, which happens when it's torn right after being
+/// created.
+pub(super) fn read_file_header(
+    gen: u64,
+    reader: &mut impl Read,
+    file_len: u64,
+) -> Result<Option<u32>> {
+    if file_len < FILE_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MAGIC {
+        return Err(KvsError::InvalidHeader { gen });
+    }
+    let version = u32_at(&header, 4);
+    if version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedVersion { gen, version });
+    }
+    Ok(Some(version))
+}
+
+/// Frame an operation as a record: the record header, followed by the payload.
+pub(super) fn encode_record(op: &Operation) -> Result<Vec<u8>> {
+    // The records used to be bare JSON, which can only be split by a streaming
+    // deserializer that reports the byte offset (serde_cbor doesn't, so CBOR
+    // was dropped for it). The records are framed with their length now, so
+    // the payload uses bincode, which is smaller and faster than both.
+    let payload = bincode::serialize(op)?;
+    if payload.len() > u32::MAX as usize {
+        return Err(KvsError::ValueTooLarge {
+            size: payload.len(),
+            max: u32::MAX as usize,
+        });
+    }
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// The length of the record `op` is framed as.
+pub(super) fn record_len(op: &Operation) -> Result<u64> {
+    Ok(RECORD_HEADER_LEN + bincode::serialized_size(op)?)
+}
+
+/// Read a record from the current position of `reader`, there are `remaining`
+/// bytes left in the segment. Returns the operation and the length of the whole
+/// record, or None if the record is truncated or its checksum mismatches.
+pub(super) fn read_record(
+    reader: &mut impl Read,
+    remaining: u64,
+) -> Result<Option<(Operation, u64)>> {
+    if remaining < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let payload_len = u64::from(u32_at(&header, 0));
+    let checksum = u32_at(&header, 4);
+    // Check the length before allocating, a corrupt header may hold anything.
+    if payload_len > remaining - RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut payload = vec![0; payload_len as usize];
+    reader.read_exact(&mut payload)?;
+    // A fuzzer can't forge the checksums, the payloads wouldn't be decoded.
+    if !cfg!(fuzzing) && crc32fast::hash(&payload) != checksum {
+        return Ok(None);
+    }
+    let op = bincode::deserialize(&payload)?;
+    Ok(Some((op, RECORD_HEADER_LEN + payload_len)))
+}
+
+/// Read the operations in a `kvs.db` of the old single file format, which is a
+/// bare sequence of JSON operations, and pass each of them to `apply`.
+///
+/// The file ends at the first operation which is incomplete or isn't valid
+/// JSON, e.g. it was being written during a power loss. Returns the length of
+/// the file up to there.
+pub(super) fn read_legacy(
+    reader: impl Read,
+    mut apply: impl FnMut(Operation) -> Result<()>,
+) -> Result<u64> {
+    let mut operations =
+        serde_json::Deserializer::from_reader(reader).into_iter::<LegacyOperation>();
+    let mut valid_len = 0;
+    loop {
+        match operations.next() {
+            None => return Ok(operations.byte_offset() as u64),
+            Some(Ok(op)) => {
+                apply(Operation::from(op))?;
+                valid_len = operations.byte_offset() as u64;
+            }
+            Some(Err(err)) if err.is_io() => return Err(err.into()),
+            Some(Err(_)) => return Ok(valid_len),
+        }
+    }
+}
+
+/// The little endian u32 at `pos` in `bytes`.
+pub(super) fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[pos..pos + 4]);
+    u32::from_le_bytes(word)
+}
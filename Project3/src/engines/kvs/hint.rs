@@ -0,0 +1,126 @@
+//! The hint file, which saves the index so `open` doesn't replay the whole log.
+//!
+//! The hint file `kvs.hint` starts with the magic number `KVSH` and the format
+//! version as a little endian u32, followed by the CRC32 of the rest of the file
+//! as a little endian u32. The rest is encoded by bincode: the generation and
+//! the length of each segment the hint covers, the number of stale bytes in
+//! them, and the index built from them.
+//!
+//! The segments are only ever appended to, so a hint still holds if its
+//! segments are all there and at least as long as they were. The segments
+//! written after it are replayed on top of it. Otherwise the hint is stale,
+//! e.g. its segments were compacted away since, and the whole log is replayed.
+
+use super::format::u32_at;
+use super::{log_path, sync_dir, Index};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 4] = *b"KVSH";
+
+/// The version of the hint format written by this crate.
+const HINT_VERSION: u32 = 1;
+
+/// The length of the magic number, the version and the checksum.
+const HEADER_LEN: usize = 12;
+
+/// The name of the hint file being written, before it's renamed into place.
+pub(super) const HINT_TEMP_NAME: &str = "kvs.hint.tmp";
+
+/// The index saved by a hint file, and the segments it was built from.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Hint {
+    /// The length of each segment when the hint was written, by generation.
+    pub(super) segments: BTreeMap<u64, u64>,
+    /// The number of stale bytes in those segments.
+    pub(super) uncompacted: u64,
+    pub(super) index: Index,
+}
+
+/// The borrowed counterpart of `Hint`, so the index isn't copied to save it.
+#[derive(Serialize)]
+struct HintRef<'a> {
+    segments: &'a BTreeMap<u64, u64>,
+    uncompacted: u64,
+    index: &'a Index,
+}
+
+/// Save the index built from `segments`, replacing the hint file.
+///
+/// The hint is written to a temporary file, which is synced and then renamed,
+/// so a crash leaves either the old hint or the new one. The segments must be
+/// synced already, the hint must never point past what survives a power loss.
+pub(super) fn write_hint(
+    dir: &Path,
+    segments: &BTreeMap<u64, u64>,
+    uncompacted: u64,
+    index: &Index,
+) -> Result<()> {
+    let payload = bincode::serialize(&HintRef {
+        segments,
+        uncompacted,
+        index,
+    })?;
+    let temp_path = dir.join(HINT_TEMP_NAME);
+    let mut file = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?,
+    );
+    file.write_all(&MAGIC)?;
+    file.write_all(&HINT_VERSION.to_le_bytes())?;
+    file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    file.write_all(&payload)?;
+    let file = file.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, hint_path(dir))?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Load the hint file if it holds for the segments `gens`, which are sorted.
+///
+/// Returns None if there's no hint, if it's corrupt, written by another
+/// version, or stale: one of its segments is gone or shorter than it was, or a
+/// segment older than its newest one isn't covered by it.
+pub(super) fn read_hint(dir: &Path, gens: &[u64]) -> Option<Hint> {
+    let bytes = fs::read(hint_path(dir)).ok()?;
+    if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC || u32_at(&bytes, 4) != HINT_VERSION {
+        return None;
+    }
+    let payload = &bytes[HEADER_LEN..];
+    if crc32fast::hash(payload) != u32_at(&bytes, 8) {
+        return None;
+    }
+    let hint: Hint = bincode::deserialize(payload).ok()?;
+    let newest = *hint.segments.keys().next_back()?;
+    let covered = hint.segments.iter().all(|(gen, &len)| {
+        gens.binary_search(gen).is_ok() && segment_len(dir, *gen).is_some_and(|now| now >= len)
+    });
+    let replayable = gens
+        .iter()
+        .all(|gen| *gen > newest || hint.segments.contains_key(gen));
+    if covered && replayable {
+        Some(hint)
+    } else {
+        None
+    }
+}
+
+fn segment_len(dir: &Path, gen: u64) -> Option<u64> {
+    fs::metadata(log_path(dir, gen))
+        .map(|metadata| metadata.len())
+        .ok()
+}
+
+fn hint_path(dir: &Path) -> PathBuf {
+    dir.join("kvs.hint")
+}
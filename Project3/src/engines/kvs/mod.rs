@@ -0,0 +1,1405 @@
+use self::format::{
+    encode_record, read_file_header, read_legacy, read_record, record_len, write_file_header,
+    Operation, FILE_HEADER_LEN, FORMAT_VERSION,
+};
+use self::hint::{read_hint, write_hint, HINT_TEMP_NAME};
+use super::KvsEngine;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use self::batch::WriteBatch;
+pub use self::options::{Options, SyncPolicy};
+pub use self::scan::{Keys, KeysBytes, Scan, ScanBytes};
+pub use self::sync::LogFile;
+use self::sync::{spawn_interval_sync, Syncer};
+
+mod batch;
+mod format;
+mod hint;
+mod options;
+mod scan;
+mod sync;
+
+/// The type for storing key-value pairs. The key and the value are both raw bytes, or
+/// strings with the `KvsEngine` wrappers, and each key must be assigned with a value.
+///
+/// All the operations are appended to a log split into numbered segment files
+/// (`1.log`, `2.log`, ...), and an in-memory index maps each key to the position
+/// of its latest value in the log. Only the segment with the highest generation
+/// is written to; the others are sealed and only read or compacted.
+///
+/// A `KvStore` is a handle which can be cloned and shared between threads. The
+/// reads use positional reads, so they run in parallel with each other and with
+/// the single writer.
+///
+/// Once enough of the log is stale, a compaction runs on a background thread
+/// while the writes go on to a new segment. Dropping the last handle waits for
+/// the running compaction.
+///
+/// Only one store at a time writes to a directory, whether in this process or
+/// another one: it holds an advisory lock on the `kvs.lock` file until its last
+/// handle is dropped. The stores opened read only take no lock, so any number
+/// of them are opened next to the writer.
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    index: Arc<RwLock<Index>>,
+    readers: Arc<RwLock<Readers>>,
+    // None if the store is opened read only.
+    writer: Option<Arc<Mutex<LogWriter>>>,
+    // The writes wait for it after releasing the writer with
+    // `SyncPolicy::GroupCommit`.
+    group_commit: Option<Arc<Syncer>>,
+    path: Arc<PathBuf>,
+    compaction: Arc<Compaction>,
+    // The background compaction works on a handle without it, so it's only
+    // dropped with the handles of the users.
+    _closer: Option<Arc<Closer>>,
+    discarded: u64,
+    // What a store opened read only loaded, which its checkpoints copy. None
+    // for the writer.
+    loaded: Option<Arc<Loaded>>,
+}
+
+/// The valid length of each segment loaded by a store opened read only, by
+/// generation, and the number of stale bytes in them.
+#[derive(Debug)]
+struct Loaded {
+    segments: BTreeMap<u64, u64>,
+    uncompacted: u64,
+}
+
+/// How many times a store is opened read only before giving up, if the writer
+/// keeps compacting the segments away while they're loaded.
+const READ_ONLY_OPEN_ATTEMPTS: u32 = 10;
+
+/// Maps each key to the position of its latest value, in the order of the keys.
+type Index = BTreeMap<Vec<u8>, LogPointer>;
+
+/// The read handles of the segments, by generation.
+type Readers = BTreeMap<u64, Arc<File>>;
+
+/// Where a record is stored: the segment generation, the offset of the record
+/// in that segment, and the length of the record.
+///
+/// `size` is how many bytes become stale once the key is overwritten. It's the
+/// length of the record, or for the operations of a batch, the length each of
+/// them would have on its own.
+///
+/// The index also keeps when the value expires, so the expired keys are skipped
+/// without reading them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct LogPointer {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    size: u64,
+    expires_at: Option<u64>,
+}
+
+impl LogPointer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The writing side of a store, shared by all the handles behind a mutex. It
+/// appends to the active segment, and it's the only one which changes the index
+/// and the segments.
+struct LogWriter {
+    index: Arc<RwLock<Index>>,
+    readers: Arc<RwLock<Readers>>,
+    writer: Arc<dyn LogFile>,
+    syncer: Arc<Syncer>,
+    path: PathBuf,
+    options: Options,
+    current_gen: u64,
+    current_len: u64,
+    uncompacted: u64,
+    // Whether the log changed since the hint file was written, so it's
+    // rewritten when the store is closed.
+    hint_stale: bool,
+    // Keeps the other writers out of the directory until the store is closed.
+    _lock: File,
+}
+
+/// The state of the background compaction, shared by all the handles.
+#[derive(Debug, Default)]
+struct Compaction {
+    state: Mutex<CompactionState>,
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CompactionState {
+    running: bool,
+    // The error of the last background compaction, reported by the next call
+    // to `compact` or `wait_for_compaction`.
+    error: Option<KvsError>,
+}
+
+impl Compaction {
+    /// Wait until no compaction is running, and hold the state.
+    fn wait_idle(&self) -> MutexGuard<'_, CompactionState> {
+        let mut state = self.state.lock().unwrap();
+        while state.running {
+            state = self.done.wait(state).unwrap();
+        }
+        state
+    }
+
+    fn finish(&self, error: Option<KvsError>) {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        if error.is_some() {
+            state.error = error;
+        }
+        self.done.notify_all();
+    }
+}
+
+/// Waits for the background compaction when the last handle is dropped, so the
+/// directory is left alone once the store is closed.
+#[derive(Debug)]
+struct Closer(Arc<Compaction>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        drop(self.0.wait_idle());
+    }
+}
+
+impl KvStore {
+    /// Open a directory to create a KvStore with the default `Options`.
+    ///
+    /// A `kvs.db` file written by the old single file format is rewritten as
+    /// the first segment. Its torn tail is left out, like the one of a segment.
+    ///
+    /// The index is loaded from the hint file, which is written by the
+    /// compactions and when the store is closed, and only the log written
+    /// after it is replayed. Without a hint, or if it's stale, the whole log
+    /// is replayed.
+    ///
+    /// If the tail of the active segment is torn or corrupt, e.g. it was being
+    /// written during a power loss, it's truncated away and the size of it is
+    /// reported by `discarded_bytes`. A corrupt record in a sealed segment is
+    /// an error.
+    pub fn open(path: impl AsRef<Path>) -> Result<KvStore> {
+        KvStore::open_with(path, &Options::new())
+    }
+
+    /// Open a directory to create a KvStore with the given `Options`.
+    ///
+    /// Returns `KvsError::StoreInUse` if another store writes to the directory,
+    /// unless it's opened read only.
+    pub fn open_with(path: impl AsRef<Path>, options: &Options) -> Result<KvStore> {
+        let mut attempts = 1;
+        loop {
+            match KvStore::try_open(path.as_ref(), options) {
+                // The writer compacted the segments away while they were loaded.
+                Err(KvsError::Io(ref err))
+                    if options.read_only
+                        && err.kind() == io::ErrorKind::NotFound
+                        && attempts < READ_ONLY_OPEN_ATTEMPTS =>
+                {
+                    attempts += 1
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Open a directory read only, e.g. to inspect a store another process
+    /// writes to. See `Options::read_only`.
+    ///
+    /// Nothing is ever created, written or compacted in the directory, not
+    /// even a torn tail is truncated. The writes are `KvsError::ReadOnly`, a
+    /// missing store is `KvsError::StoreNotFound`, and a `kvs.db` of the old
+    /// single file format is `KvsError::UpgradeRequired`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine, KvsError};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    ///
+    /// let reader = KvStore::open_read_only(dir.path()).unwrap();
+    /// assert_eq!(Some("value".to_owned()), reader.get("key".to_owned()).unwrap());
+    /// assert!(matches!(
+    ///     reader.remove("key".to_owned()),
+    ///     Err(KvsError::ReadOnly)
+    /// ));
+    /// ```
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<KvStore> {
+        KvStore::open_with(path, Options::new().read_only(true))
+    }
+
+    fn try_open(path: &Path, options: &Options) -> Result<KvStore> {
+        let path = path.to_path_buf();
+        let legacy = path.join("kvs.db");
+        let mut gens = if path.is_dir() {
+            sorted_gens(&path)?
+        } else {
+            Vec::new()
+        };
+        let exists = !gens.is_empty() || legacy.exists();
+        if exists && options.error_if_exists {
+            return Err(KvsError::StoreExists);
+        }
+        if !exists && (options.read_only || !options.create_if_missing) {
+            return Err(KvsError::StoreNotFound);
+        }
+
+        let mut lock = None;
+        let mut discarded = 0;
+        if options.read_only {
+            if gens.is_empty() {
+                // The legacy log has to be upgraded before it can be read.
+                return Err(KvsError::UpgradeRequired);
+            }
+        } else {
+            fs::create_dir_all(&path)?;
+            lock = Some(lock_dir(&path)?);
+            // Another writer may have changed the directory until now.
+            gens = sorted_gens(&path)?;
+            remove_temp_files(&path)?;
+            if legacy.exists() {
+                if gens.is_empty() {
+                    discarded = upgrade_legacy(&path, &legacy)?;
+                    gens.push(1);
+                } else {
+                    // The upgrade was interrupted after the segment is in place.
+                    fs::remove_file(&legacy)?;
+                }
+            }
+        }
+
+        let hint = read_hint(&path, &gens);
+        let mut hint_stale = hint.is_none();
+        let (mut index, mut uncompacted, covered) = match hint {
+            Some(hint) => (hint.index, hint.uncompacted, hint.segments),
+            None => (BTreeMap::new(), 0, BTreeMap::new()),
+        };
+        let mut readers = BTreeMap::new();
+        let mut segments = BTreeMap::new();
+        let mut active_version = FORMAT_VERSION;
+        for &gen in &gens {
+            let mut reader = File::open(log_path(&path, gen))?;
+            let start = covered.get(&gen).cloned().unwrap_or(0);
+            let (stale, valid_len, version) = load(gen, &mut reader, &mut index, start)?;
+            active_version = version.unwrap_or(FORMAT_VERSION);
+            if valid_len > start.max(FILE_HEADER_LEN) {
+                hint_stale = true;
+            }
+            let file_len = reader.metadata()?.len();
+            if valid_len < file_len {
+                if Some(&gen) != gens.last() {
+                    return Err(KvsError::Corrupted {
+                        gen,
+                        pos: valid_len,
+                    });
+                }
+                if !options.read_only {
+                    let file = OpenOptions::new().write(true).open(log_path(&path, gen))?;
+                    file.set_len(valid_len)?;
+                    file.sync_all()?;
+                }
+                discarded += file_len - valid_len;
+            }
+            uncompacted += stale;
+            readers.insert(gen, Arc::new(reader));
+            segments.insert(gen, valid_len);
+        }
+        let loaded = if options.read_only {
+            Some(Arc::new(Loaded {
+                segments,
+                uncompacted,
+            }))
+        } else {
+            None
+        };
+
+        let index = Arc::new(RwLock::new(index));
+        let readers = Arc::new(RwLock::new(readers));
+        let mut group_commit = None;
+        let writer = if let Some(lock) = lock {
+            let current_gen = gens.last().cloned().unwrap_or(1);
+            let (writer, current_len) = new_segment(
+                &path,
+                current_gen,
+                options.sync,
+                &mut readers.write().unwrap(),
+            )?;
+            let writer = options.wrap_log_file(writer);
+            let syncer = Arc::new(Syncer::new(Arc::clone(&writer)));
+            match options.sync {
+                SyncPolicy::Interval(interval) => {
+                    spawn_interval_sync(Arc::downgrade(&syncer), interval)
+                }
+                SyncPolicy::GroupCommit => group_commit = Some(Arc::clone(&syncer)),
+                _ => (),
+            }
+            let mut writer = LogWriter {
+                index: Arc::clone(&index),
+                readers: Arc::clone(&readers),
+                writer,
+                syncer,
+                path: path.clone(),
+                options: options.clone(),
+                current_gen,
+                current_len,
+                uncompacted,
+                hint_stale,
+                _lock: lock,
+            };
+            writer.remove_stale_segments()?;
+            // A segment of an older format is never appended to.
+            if writer.current_len >= writer.options.segment_size || active_version < FORMAT_VERSION
+            {
+                writer.roll()?;
+            }
+            Some(Arc::new(Mutex::new(writer)))
+        } else {
+            None
+        };
+        let compaction = Arc::new(Compaction::default());
+        Ok(KvStore {
+            index,
+            readers,
+            writer,
+            group_commit,
+            path: Arc::new(path),
+            _closer: Some(Arc::new(Closer(Arc::clone(&compaction)))),
+            compaction,
+            discarded,
+            loaded,
+        })
+    }
+
+    /// The number of bytes of a torn or corrupt tail discarded when the store
+    /// was opened.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded
+    }
+
+    /// The number of keys in the index. The expired keys are counted until a
+    /// compaction drops them, so unlike a scan it doesn't change as they
+    /// expire, e.g. to check a copy of the store holds all of its keys.
+    pub fn indexed_keys(&self) -> usize {
+        self.index.read().unwrap().len()
+    }
+
+    /// Compact the log now, and wait for it to finish. If a compaction is
+    /// running in the background, wait for it first.
+    ///
+    /// Returns the error of the last background compaction if it failed.
+    pub fn compact(&self) -> Result<()> {
+        self.writer()?;
+        let mut state = self.compaction.wait_idle();
+        if let Some(err) = state.error.take() {
+            return Err(err);
+        }
+        state.running = true;
+        drop(state);
+
+        let result = self.run_compaction();
+        self.compaction.finish(None);
+        result
+    }
+
+    /// Write a consistent copy of the store as it is now into the directory
+    /// `dest`, which must not exist. It's opened like any store afterwards,
+    /// and it's removed if the checkpoint fails.
+    ///
+    /// The active segment is sealed, so the copy holds every write made
+    /// before the call and none made after it, and the writes go on right
+    /// away. The segments are immutable once sealed, so they're hard linked
+    /// into `dest` where the file system allows it, and copied otherwise. The
+    /// newest one is always copied, the copy appends to it when it's opened. The
+    /// compactions wait until they're all in place, and the index is saved
+    /// with them as a hint file.
+    ///
+    /// A store opened read only copies the log as it loaded it, so a live
+    /// store is backed up next to the process writing to it. Its newest
+    /// segment is copied up to the last valid record, and the segments
+    /// compacted away since are copied from the handles it keeps open.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// # let backup_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// let backup = backup_dir.path().join("backup");
+    /// store.checkpoint(&backup).unwrap();
+    /// store.set("key".to_owned(), "new value".to_owned()).unwrap();
+    ///
+    /// let copy = KvStore::open(&backup).unwrap();
+    /// assert_eq!(Some("value".to_owned()), copy.get("key".to_owned()).unwrap());
+    /// ```
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        if self.writer.is_none() {
+            // The store never changes, nothing has to wait.
+            return self.write_checkpoint(dest.as_ref());
+        }
+        // No compaction deletes the segments while they're linked.
+        self.compaction.wait_idle().running = true;
+        let result = self.write_checkpoint(dest.as_ref());
+        self.compaction.finish(None);
+        result
+    }
+
+    fn write_checkpoint(&self, dest: &Path) -> Result<()> {
+        let (segments, uncompacted, index) = match (&self.writer, &self.loaded) {
+            (Some(writer), _) => writer.lock().unwrap().seal_for_checkpoint()?,
+            (None, Some(loaded)) => (
+                loaded.segments.clone(),
+                loaded.uncompacted,
+                self.index.read().unwrap().clone(),
+            ),
+            (None, None) => unreachable!("a store is either written or loaded"),
+        };
+        fs::create_dir(dest)?;
+        let result = self.write_segments(dest, &segments, uncompacted, &index);
+        if result.is_err() {
+            // A failed checkpoint leaves nothing behind.
+            let _ = fs::remove_dir_all(dest);
+        }
+        result
+    }
+
+    /// Write the segments of a checkpoint and its hint into `dest`.
+    fn write_segments(
+        &self,
+        dest: &Path,
+        segments: &BTreeMap<u64, u64>,
+        uncompacted: u64,
+        index: &Index,
+    ) -> Result<()> {
+        // The readers keep the segments compacted away by another process
+        // since they were loaded.
+        let readers = self.readers.read().unwrap().clone();
+        let newest = segments.keys().next_back().copied();
+        for (&gen, &len) in segments {
+            let (from, to) = (log_path(&self.path, gen), log_path(dest, gen));
+            // The newest segment is appended to when the copy is opened, so it
+            // mustn't share its inode with the store. It may be the active
+            // segment of another process too, which is copied up to the last
+            // record loaded.
+            if Some(gen) == newest || fs::hard_link(&from, &to).is_err() {
+                let reader = readers.get(&gen).expect("Cannot find log reader");
+                copy_prefix(reader, &to, len)?;
+            }
+            File::open(&to)?.sync_all()?;
+        }
+        sync_dir(dest)?;
+        write_hint(dest, segments, uncompacted, index)
+    }
+
+    /// Wait for the running background compaction, if any.
+    ///
+    /// Returns the error of the last background compaction if it failed.
+    pub fn wait_for_compaction(&self) -> Result<()> {
+        match self.compaction.wait_idle().error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// The writer of the store, unless it's opened read only.
+    fn writer(&self) -> Result<&Mutex<LogWriter>> {
+        self.writer.as_deref().ok_or(KvsError::ReadOnly)
+    }
+
+    /// Iterate over the keys in `range` and their values, in the order of the
+    /// keys.
+    ///
+    /// The values are read lazily, and the keys are fetched from the index a
+    /// few at a time, so the writes go on while iterating. A key written in the
+    /// meantime may or may not be seen.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// for key in &["a", "b", "c"] {
+    ///     store.set(key.to_string(), key.to_uppercase()).unwrap();
+    /// }
+    /// let pairs: Vec<_> = store
+    ///     .scan("b".to_owned()..)
+    ///     .collect::<kvs::Result<_>>()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     pairs,
+    ///     vec![
+    ///         ("b".to_owned(), "B".to_owned()),
+    ///         ("c".to_owned(), "C".to_owned())
+    ///     ]
+    /// );
+    /// ```
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Scan {
+        let bytes = |bound: Bound<&String>| bound.map(|key| key.clone().into_bytes());
+        Scan::new(self.scan_bytes((bytes(range.start_bound()), bytes(range.end_bound()))))
+    }
+
+    /// Iterate over the keys starting with `prefix` and their values, in the
+    /// order of the keys. See `scan`.
+    pub fn scan_prefix(&self, prefix: String) -> Scan {
+        Scan::new(self.scan_prefix_bytes(prefix.into_bytes()))
+    }
+
+    /// Iterate over the keys in `range` and their values as raw bytes. See
+    /// `scan`.
+    pub fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> ScanBytes {
+        ScanBytes::new(
+            self.clone(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            None,
+        )
+    }
+
+    /// Iterate over the keys starting with `prefix` and their values as raw
+    /// bytes. See `scan`.
+    pub fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> ScanBytes {
+        ScanBytes::new(
+            self.clone(),
+            Bound::Included(prefix.clone()),
+            Bound::Unbounded,
+            Some(prefix),
+        )
+    }
+
+    /// Store a key with its value, which expires after `ttl`. An expired key is
+    /// treated as absent, and it's dropped by the next compaction.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::time::Duration;
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store
+    ///     .set_with_ttl("session".to_owned(), "token".to_owned(), Duration::from_secs(60))
+    ///     .unwrap();
+    /// assert_eq!(Some("token".to_owned()), store.get("session".to_owned()).unwrap());
+    /// ```
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Store a key with its value as raw bytes, which expires after `ttl`. See
+    /// `set_with_ttl`.
+    pub fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expires_after(ttl);
+        self.write_with(|writer| writer.set(key, value, Some(expires_at)))
+    }
+
+    /// Make an existing key expire after `ttl`, keeping its value. Return
+    /// `KvsError::KeyNotFound` if the key doesn't exist.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::time::Duration;
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set("session".to_owned(), "token".to_owned()).unwrap();
+    /// store.expire("session".to_owned(), Duration::from_secs(0)).unwrap();
+    /// assert_eq!(None, store.get("session".to_owned()).unwrap());
+    /// ```
+    pub fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        self.expire_bytes(key.as_bytes(), ttl)
+    }
+
+    /// Make an existing key given as raw bytes expire after `ttl`. See
+    /// `expire`.
+    pub fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = expires_after(ttl);
+        self.write_with(|writer| writer.expire(key.to_vec(), expires_at))
+    }
+
+    /// Apply all the operations of a batch at once. They're saved as a single
+    /// record, so after a crash either all of them or none of them are in the
+    /// store.
+    ///
+    /// Unlike `remove`, removing a key which doesn't exist isn't an error in a
+    /// batch. Nothing is written if a key or a value is too large.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine, WriteBatch};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set("from".to_owned(), "10".to_owned()).unwrap();
+    /// let mut batch = WriteBatch::new();
+    /// batch
+    ///     .set("from".to_owned(), "0".to_owned())
+    ///     .set("to".to_owned(), "10".to_owned());
+    /// store.write(batch).unwrap();
+    /// assert_eq!(Some("10".to_owned()), store.get("to".to_owned()).unwrap());
+    /// ```
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_with(|writer| writer.write_batch(batch.ops))
+    }
+
+    /// Make a write with the writer, which returns the sequence number of the
+    /// write. Wait for it to be synced with `SyncPolicy::GroupCommit`, and
+    /// start a compaction if it's needed.
+    fn write_with(&self, write: impl FnOnce(&mut LogWriter) -> Result<u64>) -> Result<()> {
+        let mut writer = self.writer()?.lock().unwrap();
+        let seq = write(&mut writer)?;
+        let needs_compaction = writer.needs_compaction();
+        drop(writer);
+        if let Some(syncer) = &self.group_commit {
+            syncer.sync(seq)?;
+        }
+        if needs_compaction {
+            self.start_compaction();
+        }
+        Ok(())
+    }
+
+    /// Start a compaction on a background thread, unless one is running.
+    fn start_compaction(&self) {
+        let mut state = self.compaction.state.lock().unwrap();
+        if state.running {
+            return;
+        }
+        state.running = true;
+        drop(state);
+
+        let store = KvStore {
+            _closer: None,
+            ..self.clone()
+        };
+        thread::spawn(move || {
+            let error = store.run_compaction().err();
+            // The store is closed if it was the last handle, the lock is
+            // released before anyone waiting for the compaction goes on.
+            let compaction = Arc::clone(&store.compaction);
+            drop(store);
+            compaction.finish(error);
+        });
+    }
+
+    /// Rewrite the live records of the sealed segments into a new segment.
+    ///
+    /// The active segment is sealed first, the live records are copied into the
+    /// generation after it, and new writes go to the generation after that, so
+    /// the compacted segment is always replayed before anything written later.
+    /// Only sealing the segment holds the writer, the writes go on while the
+    /// records are copied. The compacted segment may exceed the segment size.
+    ///
+    /// The records are copied into a temporary file, which is synced and then
+    /// renamed to the compacted segment, so a crash never leaves a partially
+    /// written segment behind. The rewritten segments are deleted oldest first
+    /// after the rename is synced; `open` deletes the ones left by a crash.
+    ///
+    /// The index is swapped in one go when the compacted segment is in place,
+    /// only for the keys which haven't been written in the meantime. The readers
+    /// keep reading the old segments until then.
+    ///
+    /// Last, the index of the compacted segment is saved as the hint file, the
+    /// writes made in the meantime are replayed on top of it.
+    fn run_compaction(&self) -> Result<()> {
+        let (compaction_gen, snapshot, readers) =
+            self.writer()?.lock().unwrap().seal_for_compaction()?;
+        let compacted = write_compacted(&self.path, compaction_gen, &snapshot, &readers)?;
+        drop(readers);
+        let compaction_file = File::open(log_path(&self.path, compaction_gen))?;
+        let compaction_len = compaction_file.metadata()?.len();
+        self.readers
+            .write()
+            .unwrap()
+            .insert(compaction_gen, Arc::new(compaction_file));
+
+        let mut wasted = 0;
+        let mut index = self.index.write().unwrap();
+        for (key, old) in snapshot {
+            let new = compacted.get(&key).cloned();
+            if index.get(&key) == Some(&old) {
+                match new {
+                    Some(pointer) => index.insert(key, pointer),
+                    // It expired, and it's gone with the compacted segments.
+                    None => index.remove(&key),
+                };
+            } else if let Some(pointer) = new {
+                wasted += pointer.size;
+            }
+        }
+        drop(index);
+
+        let mut readers = self.readers.write().unwrap();
+        let stale_gens: Vec<u64> = readers
+            .keys()
+            .cloned()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        for gen in stale_gens {
+            readers.remove(&gen);
+            fs::remove_file(log_path(&self.path, gen))?;
+        }
+        drop(readers);
+        self.writer()?.lock().unwrap().uncompacted += wasted;
+
+        let segments = BTreeMap::from([(compaction_gen, compaction_len)]);
+        write_hint(&self.path, &segments, 0, &compacted)
+    }
+}
+
+impl LogWriter {
+    /// Set a key, which expires at `expires_at` if it's given. Returns the
+    /// sequence number of the write.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<u64> {
+        self.check_size(&key, &value)?;
+        let op = match expires_at {
+            Some(expires_at) => Operation::SetEx {
+                key,
+                value,
+                expires_at,
+            },
+            None => Operation::Set { key, value },
+        };
+        self.write(op)
+    }
+
+    /// Set a key to the value it has, expiring at `expires_at`. Returns the
+    /// sequence number of the write.
+    fn expire(&mut self, key: Vec<u8>, expires_at: u64) -> Result<u64> {
+        // The writes are serialized by the writer, so the value read is still
+        // the latest. A background compaction may move it to another segment
+        // though, so the reader is taken while holding the index, like `get`.
+        let (pointer, reader) = {
+            let index = self.index.read().unwrap();
+            let pointer = match index.get(&key) {
+                Some(pointer) if !pointer.is_expired(now_millis()) => *pointer,
+                _ => return Err(KvsError::KeyNotFound),
+            };
+            let readers = self.readers.read().unwrap();
+            let reader = readers
+                .get(&pointer.gen)
+                .cloned()
+                .expect("Cannot find log reader");
+            (pointer, reader)
+        };
+        let value = latest_for(read_at(&reader, pointer)?, &key)
+            .and_then(Operation::into_value)
+            .ok_or(KvsError::KeyNotFound)?;
+        self.set(key, value, Some(expires_at))
+    }
+
+    /// Returns the sequence number of the write.
+    fn remove(&mut self, key: Vec<u8>) -> Result<u64> {
+        // Nobody else changes the index, so the key can't be set in the meantime.
+        let exists = self
+            .index
+            .read()
+            .unwrap()
+            .get(&key)
+            .is_some_and(|pointer| !pointer.is_expired(now_millis()));
+        if !exists {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write(Operation::Rm { key })
+    }
+
+    /// Returns the sequence number of the write.
+    fn write_batch(&mut self, ops: Vec<Operation>) -> Result<u64> {
+        for op in &ops {
+            if let Operation::Set { key, value } = op {
+                self.check_size(key, value)?;
+            }
+        }
+        self.write(Operation::Batch(ops))
+    }
+
+    /// Log an operation and apply it to the index. Returns the sequence number
+    /// of the write.
+    fn write(&mut self, op: Operation) -> Result<u64> {
+        let (pointer, seq) = self.log(&op)?;
+        self.uncompacted += apply(&mut self.index.write().unwrap(), op, pointer)?;
+        Ok(seq)
+    }
+
+    fn check_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > self.options.max_key_size {
+            return Err(KvsError::KeyTooLarge {
+                size: key.len(),
+                max: self.options.max_key_size,
+            });
+        }
+        if value.len() > self.options.max_value_size {
+            return Err(KvsError::ValueTooLarge {
+                size: value.len(),
+                max: self.options.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.uncompacted > self.options.compaction_threshold
+    }
+
+    /// Delete the oldest sealed segments which don't hold any live record.
+    ///
+    /// They are left behind when a compaction is interrupted after the compacted
+    /// segment is in place, but before the segments it rewrote are deleted.
+    /// Only a prefix of the segments is deleted, because a segment without live
+    /// records may still hold a removal which hides a record in an older segment.
+    fn remove_stale_segments(&mut self) -> Result<()> {
+        let live_gens: HashSet<u64> = self
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .map(|pointer| pointer.gen)
+            .collect();
+        let current_gen = self.current_gen;
+        let mut readers = self.readers.write().unwrap();
+        let stale_gens: Vec<u64> = readers
+            .keys()
+            .cloned()
+            .take_while(|gen| *gen < current_gen && !live_gens.contains(gen))
+            .collect();
+        for gen in stale_gens {
+            if let Some(reader) = readers.remove(&gen) {
+                let len = reader.metadata()?.len();
+                self.uncompacted = self.uncompacted.saturating_sub(len);
+            }
+            fs::remove_file(log_path(&self.path, gen))?;
+            self.hint_stale = true;
+        }
+        Ok(())
+    }
+
+    /// Save the index as the hint file, covering all the segments. They're
+    /// synced first, whatever the `SyncPolicy`.
+    fn write_hint(&mut self) -> Result<()> {
+        let readers = self.readers.read().unwrap();
+        let mut segments = BTreeMap::new();
+        for (&gen, reader) in readers.iter() {
+            reader.sync_data()?;
+            segments.insert(gen, reader.metadata()?.len());
+        }
+        write_hint(
+            &self.path,
+            &segments,
+            self.uncompacted,
+            &self.index.read().unwrap(),
+        )?;
+        drop(readers);
+        self.hint_stale = false;
+        Ok(())
+    }
+
+    /// Whether the writes are synced in batches by the `Syncer`.
+    fn syncs_in_batches(&self) -> bool {
+        matches!(
+            self.options.sync,
+            SyncPolicy::Interval(_) | SyncPolicy::GroupCommit
+        )
+    }
+
+    /// Save an operation into the active segment, and return where it's stored
+    /// and the sequence number of the write.
+    fn log(&mut self, op: &Operation) -> Result<(LogPointer, u64)> {
+        let record = encode_record(op)?;
+        self.writer.append(&record)?;
+        if self.options.sync == SyncPolicy::Always {
+            self.writer.sync_data()?;
+        }
+        let seq = self.syncer.wrote();
+        self.hint_stale = true;
+        let pointer = LogPointer {
+            gen: self.current_gen,
+            pos: self.current_len,
+            len: record.len() as u64,
+            size: record.len() as u64,
+            expires_at: None,
+        };
+        self.current_len += pointer.len;
+        if self.current_len >= self.options.segment_size {
+            self.roll()?;
+        }
+        Ok((pointer, seq))
+    }
+
+    /// Seal the active segment and start writing to the next generation.
+    ///
+    /// The writes waiting for a batched sync are synced first, the next syncs
+    /// only cover the new segment.
+    fn roll(&mut self) -> Result<()> {
+        if self.syncs_in_batches() && self.syncer.is_dirty() {
+            self.writer.sync_data()?;
+        }
+        self.current_gen += 1;
+        let (writer, len) = new_segment(
+            &self.path,
+            self.current_gen,
+            self.options.sync,
+            &mut self.readers.write().unwrap(),
+        )?;
+        self.writer = self.options.wrap_log_file(writer);
+        self.syncer.rolled(Arc::clone(&self.writer));
+        self.current_len = len;
+        Ok(())
+    }
+
+    /// Seal the active segment, and leave the generation after it for the
+    /// compacted segment. Returns that generation, and snapshots of the index
+    /// and the readers to compact.
+    fn seal_for_compaction(&mut self) -> Result<(u64, Index, Readers)> {
+        let compaction_gen = self.current_gen + 1;
+        self.current_gen = compaction_gen;
+        self.roll()?;
+        // All the stale records are in the segments to compact now.
+        self.uncompacted = 0;
+        let index = self.index.read().unwrap().clone();
+        let readers = self.readers.read().unwrap().clone();
+        Ok((compaction_gen, index, readers))
+    }
+
+    /// Seal the active segment for a checkpoint. Returns the length of each
+    /// segment to copy by generation, the number of stale bytes in them, and a
+    /// snapshot of the index.
+    fn seal_for_checkpoint(&mut self) -> Result<(BTreeMap<u64, u64>, u64, Index)> {
+        let sealed_gen = self.current_gen;
+        self.roll()?;
+        let readers = self.readers.read().unwrap();
+        let mut segments = BTreeMap::new();
+        for (&gen, reader) in readers.range(..=sealed_gen) {
+            segments.insert(gen, reader.metadata()?.len());
+        }
+        let index = self.index.read().unwrap().clone();
+        Ok((segments, self.uncompacted, index))
+    }
+}
+
+impl Drop for LogWriter {
+    /// Sync the writes left by the batched syncs when the store is closed, and
+    /// save the index as the hint file if the log changed.
+    fn drop(&mut self) {
+        if self.syncs_in_batches() {
+            let _ = self.syncer.sync_all();
+        }
+        if self.hint_stale {
+            // The log is replayed instead if it fails.
+            let _ = self.write_hint();
+        }
+    }
+}
+
+impl fmt::Debug for LogWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogWriter")
+            .field("path", &self.path)
+            .field("options", &self.options)
+            .field("current_gen", &self.current_gen)
+            .field("current_len", &self.current_len)
+            .field("uncompacted", &self.uncompacted)
+            .field("hint_stale", &self.hint_stale)
+            .finish()
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Store a key with it's value, this will store a key and it's value to the storage.
+    /// If the key has already been exist, the value will be overwrited.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set_bytes(b"key".to_vec(), vec![0xff, 0x00]).unwrap();
+    /// assert_eq!(Some(vec![0xff, 0x00]), store.get_bytes(b"key").unwrap());
+    /// ```
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_with(|writer| writer.set(key, value, None))
+    }
+
+    /// Get a key's value.
+    /// Return Some(_) if the key exists, return None otherwise.
+    /// The return value is a copy of the stored value, so it won't delete the origin data.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Take the reader while holding the index, so the segment can't be
+        // compacted away in between. Reading from it is done without any lock.
+        let (pointer, reader) = {
+            let index = self.index.read().unwrap();
+            let pointer = match index.get(key) {
+                Some(pointer) if !pointer.is_expired(now_millis()) => *pointer,
+                _ => return Ok(None),
+            };
+            let readers = self.readers.read().unwrap();
+            let reader = readers
+                .get(&pointer.gen)
+                .cloned()
+                .expect("Cannot find log reader");
+            (pointer, reader)
+        };
+        Ok(latest_for(read_at(&reader, pointer)?, key).and_then(Operation::into_value))
+    }
+
+    /// Remove a key's value
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write_with(|writer| writer.remove(key.to_vec()))
+    }
+}
+
+///  Reads a whole segment, one command at a time, recording the affected key and
+///  file offset of the command to an in-memory key -> log pointer map. The
+///  records before `start` are skipped, they're in the index already.
+///
+///  Returns how many bytes in the log become stale, the length of the valid
+///  records, and the format version of the segment. Anything after the valid
+///  records is a torn or corrupt tail.
+fn load(
+    gen: u64,
+    reader: &mut File,
+    store: &mut Index,
+    start: u64,
+) -> Result<(u64, u64, Option<u32>)> {
+    let file_len = reader.metadata()?.len();
+    reader.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(reader);
+    let version = match read_file_header(gen, &mut reader, file_len)? {
+        Some(version) => version,
+        None => return Ok((0, 0, None)),
+    };
+    let mut uncompacted = 0;
+    let mut pos = start.max(FILE_HEADER_LEN);
+    reader.seek(SeekFrom::Start(pos))?;
+    while pos < file_len {
+        let (op, len) = match read_record(&mut reader, file_len - pos)? {
+            Some(record) => record,
+            None => break,
+        };
+        let pointer = LogPointer {
+            gen,
+            pos,
+            len,
+            size: len,
+            expires_at: None,
+        };
+        uncompacted += apply(store, op, pointer)?;
+        pos += len;
+    }
+    Ok((uncompacted, pos, Some(version)))
+}
+
+/// Apply an operation stored at `pointer` to the index, and return how many
+/// bytes in the log become stale.
+fn apply(index: &mut Index, op: Operation, pointer: LogPointer) -> Result<u64> {
+    let mut stale = 0;
+    let expires_at = op.expires_at();
+    match op {
+        Operation::Set { key, .. } | Operation::SetEx { key, .. } => {
+            if let Some(old) = index.insert(
+                key,
+                LogPointer {
+                    expires_at,
+                    ..pointer
+                },
+            ) {
+                stale += old.size;
+            }
+        }
+        Operation::Rm { key } => {
+            if let Some(old) = index.remove(&key) {
+                stale += old.size;
+            }
+            stale += pointer.size;
+        }
+        Operation::Batch(ops) => {
+            for op in ops {
+                let size = record_len(&op)?;
+                stale += apply(index, op, LogPointer { size, ..pointer })?;
+            }
+        }
+        Operation::Get { .. } => (),
+    };
+    Ok(stale)
+}
+
+/// The operation on `key` in a record: the record itself, or the last
+/// operation on the key in a batch.
+fn latest_for(op: Operation, key: &[u8]) -> Option<Operation> {
+    match op {
+        Operation::Batch(ops) => ops.into_iter().rev().find(|op| op.key() == Some(key)),
+        op => Some(op),
+    }
+}
+
+/// Rewrite the single file log of the old format, which is a bare sequence of
+/// JSON operations, as the first segment. Returns the length of its torn tail,
+/// which is left out.
+fn upgrade_legacy(dir: &Path, legacy: &Path) -> Result<u64> {
+    let temp_path = temp_path(dir, 1);
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?,
+    );
+    let reader = File::open(legacy)?;
+    let file_len = reader.metadata()?.len();
+    write_file_header(&mut writer)?;
+    let valid_len = read_legacy(BufReader::new(reader), |op| {
+        writer.write_all(&encode_record(&op)?)?;
+        Ok(())
+    })?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, log_path(dir, 1))?;
+    sync_dir(dir)?;
+    fs::remove_file(legacy)?;
+    Ok(file_len - valid_len)
+}
+
+/// Copy the records in `index` into the compacted segment `gen`, and return
+/// the index of the compacted segment.
+///
+/// The operations of a batch are copied as records on their own, the batch is
+/// complete so they don't need to be applied together any more. The expired
+/// keys are left out.
+fn write_compacted(dir: &Path, gen: u64, index: &Index, readers: &Readers) -> Result<Index> {
+    let temp_path = temp_path(dir, gen);
+    let mut compact_file = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?,
+    );
+    write_file_header(&mut compact_file)?;
+    let mut compacted = BTreeMap::new();
+    let mut compact_len = FILE_HEADER_LEN;
+    let mut record = Vec::new();
+    let now = now_millis();
+    for (key, pointer) in index {
+        if pointer.is_expired(now) {
+            continue;
+        }
+        let reader = readers.get(&pointer.gen).expect("Cannot find log reader");
+        if pointer.size == pointer.len {
+            record.resize(pointer.len as usize, 0);
+            read_exact_at(reader, &mut record, pointer.pos)?;
+        } else {
+            // Only the operations of a batch are smaller than their record.
+            let op = latest_for(read_at(reader, *pointer)?, key).ok_or(KvsError::Corrupted {
+                gen: pointer.gen,
+                pos: pointer.pos,
+            })?;
+            record = encode_record(&op)?;
+        }
+        compact_file.write_all(&record)?;
+        let len = record.len() as u64;
+        let new_pointer = LogPointer {
+            gen,
+            pos: compact_len,
+            len,
+            size: len,
+            expires_at: pointer.expires_at,
+        };
+        compacted.insert(key.clone(), new_pointer);
+        compact_len += len;
+    }
+    let compact_file = compact_file.into_inner().map_err(|err| err.into_error())?;
+    compact_file.sync_all()?;
+    drop(compact_file);
+
+    fs::rename(&temp_path, log_path(dir, gen))?;
+    sync_dir(dir)?;
+    Ok(compacted)
+}
+
+/// Read the record `pointer` points to.
+fn read_at(reader: &File, pointer: LogPointer) -> Result<Operation> {
+    let mut record = vec![0; pointer.len as usize];
+    read_exact_at(reader, &mut record, pointer.pos)?;
+    match read_record(&mut record.as_slice(), pointer.len)? {
+        Some((op, _)) => Ok(op),
+        None => Err(KvsError::Corrupted {
+            gen: pointer.gen,
+            pos: pointer.pos,
+        }),
+    }
+}
+
+/// Open the segment `gen` for appending, and register a reader of it.
+/// Returns the writer and the current length of the segment.
+///
+/// If the segment is created, the directory is synced too unless the writes are
+/// never synced. Otherwise the synced writes could be lost with its entry.
+fn new_segment(
+    dir: &Path,
+    gen: u64,
+    sync: SyncPolicy,
+    readers: &mut Readers,
+) -> Result<(File, u64)> {
+    let path = log_path(dir, gen);
+    let mut writer = OpenOptions::new().append(true).create(true).open(&path)?;
+    let mut len = writer.metadata()?.len();
+    if len == 0 {
+        write_file_header(&mut writer)?;
+        len = FILE_HEADER_LEN;
+        if sync != SyncPolicy::None {
+            sync_dir(dir)?;
+        }
+    }
+    readers.insert(gen, Arc::new(File::open(&path)?));
+    Ok((writer, len))
+}
+
+/// The generations of all the segments in the directory, in ascending order.
+fn sorted_gens(path: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("log")) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                gens.push(gen);
+            }
+        }
+    }
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// Take the lock of the writer of the store in `dir`, which is held as long as
+/// the file is open.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join("kvs.lock"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::StoreInUse),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Remove the temporary files left by an interrupted compaction, or by an
+/// interrupted write of the hint file.
+fn remove_temp_files(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let is_temp = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.ends_with(".log.tmp") || name == HINT_TEMP_NAME);
+        if is_temp && path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read exactly `buf.len()` bytes at `pos`, without moving the file cursor, so
+/// it can be shared between threads.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, pos)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                pos += n as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Copy the first `len` bytes of `file` into the new file `to`.
+fn copy_prefix(file: &File, to: &Path, len: u64) -> Result<()> {
+    let mut writer = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(to)?);
+    let mut buf = vec![0; 64 * 1024];
+    let mut pos = 0;
+    while pos < len {
+        let chunk = (len - pos).min(buf.len() as u64) as usize;
+        read_exact_at(file, &mut buf[..chunk], pos)?;
+        writer.write_all(&buf[..chunk])?;
+        pos += chunk as u64;
+    }
+    writer.into_inner().map_err(|err| err.into_error())?;
+    Ok(())
+}
+
+/// Make the files created, renamed and deleted in the directory durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be opened as files on other platforms, renames there are
+/// made durable by the file system itself.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// When a key set now with a TTL of `ttl` expires, in milliseconds since the
+/// Unix epoch. A TTL too long for it never expires.
+fn expires_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+fn temp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.tmp", gen))
+}
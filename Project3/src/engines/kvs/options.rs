@@ -0,0 +1,165 @@
+use super::sync::LogFile;
+use std::fmt;
+use std::fs::File;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The default number of stale bytes in the log which triggers a compaction.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// The default size at which the active segment is sealed and a new one started.
+const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// When the writes are synced to the disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Each write is handed to the OS but never synced, so the acknowledged
+    /// writes survive the process crashing, but not a power loss.
+    None,
+    /// Each write is synced before it's acknowledged.
+    Always,
+    /// The writes are synced on a background thread every given interval, so
+    /// at most that much of the acknowledged writes is lost on a power loss.
+    Interval(Duration),
+    /// Each write is synced before it's acknowledged, but the writers which
+    /// come in while a sync is running wait for it and are all synced by the
+    /// next one.
+    GroupCommit,
+}
+
+/// Options to configure how a `KvStore` is opened and how it behaves. Passed to
+/// `KvStore::open_with`, and `KvStore::open` uses the defaults.
+///
+/// # Example
+///
+/// ```
+/// use kvs::{KvStore, Options, SyncPolicy};
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open_with(
+///     dir.path(),
+///     Options::new()
+///         .segment_size(64 * 1024)
+///         .sync(SyncPolicy::Always),
+/// )
+/// .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(super) log_file: Option<LogFileWrapper>,
+    pub(super) compaction_threshold: u64,
+    pub(super) segment_size: u64,
+    pub(super) sync: SyncPolicy,
+    pub(super) create_if_missing: bool,
+    pub(super) error_if_exists: bool,
+    pub(super) read_only: bool,
+    pub(super) max_key_size: usize,
+    pub(super) max_value_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            log_file: None,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync: SyncPolicy::None,
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+        }
+    }
+}
+
+impl Options {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// A compaction starts once there are more than `threshold` stale bytes in
+    /// the log. 1 MiB by default.
+    pub fn compaction_threshold(&mut self, threshold: u64) -> &mut Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// The active segment is sealed once it exceeds `size` bytes. 4 MiB by default.
+    pub fn segment_size(&mut self, size: u64) -> &mut Self {
+        self.segment_size = size;
+        self
+    }
+
+    /// When the writes are synced to the disk. `SyncPolicy::None` by default.
+    pub fn sync(&mut self, sync: SyncPolicy) -> &mut Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Wrap the files of the active segments, e.g. to observe or fake the
+    /// syncs. The files are used directly by default.
+    pub fn log_file<F>(&mut self, wrap: F) -> &mut Self
+    where
+        F: Fn(File) -> Box<dyn LogFile> + Send + Sync + 'static,
+    {
+        self.log_file = Some(LogFileWrapper(Arc::new(wrap)));
+        self
+    }
+
+    /// Create the store, and the directory, if there's no store in it. True by
+    /// default. Otherwise opening a missing store is `KvsError::StoreNotFound`.
+    pub fn create_if_missing(&mut self, create_if_missing: bool) -> &mut Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Opening an existing store is `KvsError::StoreExists`. False by default.
+    pub fn error_if_exists(&mut self, error_if_exists: bool) -> &mut Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Open the store without writing anything to the directory: the writes
+    /// are `KvsError::ReadOnly`, and nothing is repaired or compacted. False
+    /// by default.
+    ///
+    /// It takes no lock, so it's opened next to the store writing to the
+    /// directory. It only sees what was written before it's opened.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Setting a key longer than `size` bytes is `KvsError::KeyTooLarge`. No
+    /// limit by default.
+    pub fn max_key_size(&mut self, size: usize) -> &mut Self {
+        self.max_key_size = size;
+        self
+    }
+
+    /// Setting a value longer than `size` bytes is `KvsError::ValueTooLarge`.
+    /// No limit by default.
+    pub fn max_value_size(&mut self, size: usize) -> &mut Self {
+        self.max_value_size = size;
+        self
+    }
+
+    /// Wrap the file of an active segment with the `log_file` option.
+    pub(super) fn wrap_log_file(&self, file: File) -> Arc<dyn LogFile> {
+        match &self.log_file {
+            Some(LogFileWrapper(wrap)) => Arc::from(wrap(file)),
+            None => Arc::new(file),
+        }
+    }
+}
+
+/// The function passed to `Options::log_file`.
+#[derive(Clone)]
+pub(super) struct LogFileWrapper(Arc<dyn Fn(File) -> Box<dyn LogFile> + Send + Sync>);
+
+impl fmt::Debug for LogFileWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogFileWrapper")
+    }
+}
@@ -0,0 +1,175 @@
+use super::format::Operation;
+use super::{latest_for, now_millis, read_at, KvStore, LogPointer};
+use crate::{KvsError, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// How many keys are fetched from the index at a time.
+const SCAN_CHUNK: usize = 128;
+
+/// An iterator over a range of keys and their values as strings, created by
+/// `KvStore::scan` or `KvStore::scan_prefix`. A key or a value which isn't a
+/// string is `KvsError::InvalidUtf8`.
+#[derive(Debug)]
+pub struct Scan(ScanBytes);
+
+/// An iterator over a range of keys as strings, which doesn't read the values.
+/// Created by `Scan::keys`.
+#[derive(Debug)]
+pub struct Keys(ScanBytes);
+
+/// An iterator over a range of keys and their values as raw bytes, created by
+/// `KvStore::scan_bytes` or `KvStore::scan_prefix_bytes`.
+#[derive(Debug)]
+pub struct ScanBytes {
+    store: KvStore,
+    // The keys after the ones fetched so far.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    prefix: Option<Vec<u8>>,
+    // The fetched keys, with the reader of the segment holding each of them,
+    // so a compaction doesn't get in the way.
+    fetched: VecDeque<(Vec<u8>, LogPointer, Arc<File>)>,
+    done: bool,
+}
+
+/// An iterator over a range of keys as raw bytes, which doesn't read the
+/// values. Created by `ScanBytes::keys`.
+#[derive(Debug)]
+pub struct KeysBytes(ScanBytes);
+
+impl Scan {
+    pub(super) fn new(scan: ScanBytes) -> Scan {
+        Scan(scan)
+    }
+
+    /// Only iterate over the keys, without reading the values.
+    pub fn keys(self) -> Keys {
+        Keys(self.0)
+    }
+}
+
+impl ScanBytes {
+    pub(super) fn new(
+        store: KvStore,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        prefix: Option<Vec<u8>>,
+    ) -> ScanBytes {
+        let done = is_empty_range(&start, &end);
+        ScanBytes {
+            store,
+            start,
+            end,
+            prefix,
+            fetched: VecDeque::new(),
+            done,
+        }
+    }
+
+    /// Only iterate over the keys, without reading the values.
+    pub fn keys(self) -> KeysBytes {
+        KeysBytes(self)
+    }
+
+    /// The next key, and where its value is stored.
+    fn next_pointer(&mut self) -> Option<(Vec<u8>, LogPointer, Arc<File>)> {
+        while self.fetched.is_empty() && !self.done {
+            self.fetch();
+        }
+        self.fetched.pop_front()
+    }
+
+    /// Fetch the next keys from the index.
+    fn fetch(&mut self) {
+        let index = self.store.index.read().unwrap();
+        let readers = self.store.readers.read().unwrap();
+        let range = index.range::<Vec<u8>, _>((self.start.as_ref(), self.end.as_ref()));
+        let now = now_millis();
+        let mut last = None;
+        let mut seen = 0;
+        for (key, pointer) in range.take(SCAN_CHUNK) {
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    self.done = true;
+                    return;
+                }
+            }
+            last = Some(key);
+            seen += 1;
+            if pointer.is_expired(now) {
+                continue;
+            }
+            let reader = readers
+                .get(&pointer.gen)
+                .cloned()
+                .expect("Cannot find log reader");
+            self.fetched.push_back((key.clone(), *pointer, reader));
+        }
+        match last {
+            Some(key) if seen == SCAN_CHUNK => self.start = Bound::Excluded(key.clone()),
+            _ => self.done = true,
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self
+            .0
+            .next()?
+            .and_then(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)));
+        Some(pair)
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.0.next_pointer()?.0;
+        Some(String::from_utf8(key).map_err(KvsError::from))
+    }
+}
+
+impl Iterator for ScanBytes {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, pointer, reader)) = self.next_pointer() {
+            let op = match read_at(&reader, pointer) {
+                Ok(op) => op,
+                Err(err) => return Some(Err(err)),
+            };
+            if let Some(value) = latest_for(op, &key).and_then(Operation::into_value) {
+                return Some(Ok((key, value)));
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for KeysBytes {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_pointer().map(|(key, ..)| key)
+    }
+}
+
+/// Whether there's no key between the bounds. `BTreeMap::range` panics on
+/// these.
+fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    use std::ops::Bound::*;
+    match (start, end) {
+        (Included(start), Included(end)) => start > end,
+        (Included(start), Excluded(end))
+        | (Excluded(start), Included(end))
+        | (Excluded(start), Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
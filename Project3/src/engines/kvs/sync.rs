@@ -0,0 +1,142 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// The active segment a `KvStore` appends its records to.
+///
+/// It's a `File` by default. `Options::log_file` wraps the files in another
+/// implementation, e.g. to observe or fake the syncs.
+pub trait LogFile: Send + Sync {
+    /// Append `buf` to the end of the file.
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// Sync everything appended so far to the disk. It may be called by one
+    /// thread while another one appends.
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        (&*self).write_all(buf)?;
+        (&*self).flush()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Tracks which writes are synced, so `SyncPolicy::Interval` and
+/// `SyncPolicy::GroupCommit` can sync a batch of writes at once.
+///
+/// Each write gets a sequence number. One thread at a time syncs the active
+/// segment, which covers all the writes made before it started; the writes
+/// coming in meanwhile wait and are covered by the next sync.
+pub(super) struct Syncer {
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+struct SyncState {
+    file: Arc<dyn LogFile>,
+    written: u64,
+    synced: u64,
+    syncing: bool,
+}
+
+impl Syncer {
+    pub(super) fn new(file: Arc<dyn LogFile>) -> Syncer {
+        Syncer {
+            state: Mutex::new(SyncState {
+                file,
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Record a write to the active segment, and return its sequence number.
+    pub(super) fn wrote(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Switch to a new active segment. The writes to the old one must be
+    /// synced already.
+    pub(super) fn rolled(&self, file: Arc<dyn LogFile>) {
+        let mut state = self.state.lock().unwrap();
+        state.file = file;
+        state.synced = state.written;
+        self.synced.notify_all();
+    }
+
+    /// Whether some writes aren't synced yet.
+    pub(super) fn is_dirty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.synced < state.written
+    }
+
+    /// Wait until the write `seq` is synced, syncing the active segment if no
+    /// other thread does.
+    pub(super) fn sync(&self, seq: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.synced < seq {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let target = state.written;
+            let file = Arc::clone(&state.file);
+            drop(state);
+            let result = file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() && state.synced < target {
+                state.synced = target;
+            }
+            self.synced.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Sync all the writes made so far.
+    pub(super) fn sync_all(&self) -> io::Result<()> {
+        let seq = self.state.lock().unwrap().written;
+        self.sync(seq)
+    }
+}
+
+impl fmt::Debug for Syncer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Syncer")
+            .field("written", &state.written)
+            .field("synced", &state.synced)
+            .finish()
+    }
+}
+
+/// Sync the writes every `interval` on a background thread, which stops once
+/// the store is closed.
+pub(super) fn spawn_interval_sync(syncer: Weak<Syncer>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let syncer = match syncer.upgrade() {
+            Some(syncer) => syncer,
+            None => break,
+        };
+        if syncer.is_dirty() {
+            // A failed sync is retried on the next tick, and the writes are
+            // synced again when the store is closed.
+            let _ = syncer.sync_all();
+        }
+    });
+}
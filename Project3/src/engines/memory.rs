@@ -0,0 +1,57 @@
+use super::KvsEngine;
+use crate::{KvsError, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// An in-memory engine backed by a HashMap. Nothing is persisted, so all the
+/// data is lost when the store is dropped.
+///
+/// It's mostly useful in tests, or as a reference to check other engines against.
+#[derive(Debug, Default, Clone)]
+pub struct MemStore {
+    store: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemStore {
+    /// Creates an empty MemStore
+    pub fn new() -> Self {
+        MemStore::default()
+    }
+}
+
+impl KvsEngine for MemStore {
+    /// Store a key with it's value, this will store a key and it's value to the storage.
+    /// If the key has already been exist, the value will be overwrited.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, MemStore};
+    /// let store = MemStore::new();
+    /// store.set_bytes(b"key".to_vec(), vec![0, 1, 2]).unwrap();
+    /// assert_eq!(Some(vec![0, 1, 2]), store.get_bytes(b"key").unwrap());
+    /// store.set_bytes(b"key".to_vec(), vec![3]).unwrap();
+    /// assert_eq!(Some(vec![3]), store.get_bytes(b"key").unwrap());
+    /// ```
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.store.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    /// Get a key's value.
+    /// Return Some(_) if the key exists, return None otherwise.
+    /// The return value is a copy of the stored value, so it won't delete the origin data.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.read().unwrap().get(key).cloned())
+    }
+
+    /// Remove a key's value
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.store
+            .write()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+}
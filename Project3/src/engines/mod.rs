@@ -0,0 +1,78 @@
+//! The storage engines. Every engine implements `KvsEngine`, so the callers
+//! can switch between them without changing their code.
+
+use crate::Result;
+
+pub use self::kvs::{
+    Keys, KeysBytes, KvStore, LogFile, Options, Scan, ScanBytes, SyncPolicy, WriteBatch,
+};
+pub use self::memory::MemStore;
+
+mod kvs;
+mod memory;
+
+/// The interface of a key-value storage engine.
+///
+/// The keys and the values are raw bytes. The methods taking and returning
+/// strings are wrappers around the ones taking bytes.
+///
+/// An engine is a handle which can be cloned and sent to other threads, all the
+/// clones share the same data.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Store a key with its value. If the key already exists, the value is
+    /// overwritten.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get a key's value.
+    /// Return Some(_) if the key exists, return None otherwise.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Remove a key's value.
+    /// Return `KvsError::KeyNotFound` if the key doesn't exist.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Store a key with it's value. If the key has already been exist, the
+    /// value will be overwrited.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value1".to_owned()).unwrap();
+    /// assert_eq!(Some("value1".to_owned()), store.get("key".to_owned()).unwrap());
+    /// store.set("key".to_owned(), "value2".to_owned()).unwrap();
+    /// assert_eq!(Some("value2".to_owned()), store.get("key".to_owned()).unwrap());
+    /// ```
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Get a key's value.
+    /// Return Some(_) if the key exists, return None otherwise.
+    /// Return `KvsError::InvalidUtf8` if the value isn't a string.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// assert_eq!(Some("value".to_owned()), store.get("key".to_owned()).unwrap());
+    /// assert_eq!(None, store.get("kkkk".to_owned()).unwrap());
+    /// ```
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a key's value.
+    /// Return `KvsError::KeyNotFound` if the key doesn't exist.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
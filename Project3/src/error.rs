@@ -0,0 +1,127 @@
+// `failure_derive` expands `Fail` into impls nested in a const block.
+#![allow(non_local_definitions)]
+
+use failure::Fail;
+
+/// The error type
+#[derive(Debug, Fail)]
+pub enum KvsError {
+    /// The passing in command is invalid, either in wrong format or the command not support
+    #[fail(display = "Invalid command {}", command)]
+    InvalidCommand {
+        /// Used to show the wrong command
+        command: String,
+    },
+    /// Removing a key which doesn't exist in the store.
+    #[fail(display = "Key not found")]
+    KeyNotFound,
+    /// Opening a store which doesn't exist, without `Options::create_if_missing`.
+    #[fail(display = "Store not found")]
+    StoreNotFound,
+    /// Opening a store which already exists, with `Options::error_if_exists`.
+    #[fail(display = "Store already exists")]
+    StoreExists,
+    /// Opening a store which another store writes to, in this process or
+    /// another one.
+    #[fail(display = "Store is in use by another writer")]
+    StoreInUse,
+    /// Writing to a store opened read only.
+    #[fail(display = "Store is opened read only")]
+    ReadOnly,
+    /// Opening read only a store of the old single file format, which is only
+    /// upgraded by opening it for writing.
+    #[fail(display = "Store must be opened for writing once to upgrade its format")]
+    UpgradeRequired,
+    /// The key is longer than `Options::max_key_size`.
+    #[fail(display = "Key too large: {} bytes, at most {}", size, max)]
+    KeyTooLarge {
+        /// The size of the key
+        size: usize,
+        /// The maximum size
+        max: usize,
+    },
+    /// The value is longer than `Options::max_value_size`, or the record doesn't
+    /// fit into the log format.
+    #[fail(display = "Value too large: {} bytes, at most {}", size, max)]
+    ValueTooLarge {
+        /// The size of the value
+        size: usize,
+        /// The maximum size
+        max: usize,
+    },
+    /// The log file is invalid. Maybe it's modified by other application.
+    #[fail(display = "Invalid file format: {}", _0)]
+    InvalidFile(#[cause] serde_json::Error),
+    /// A record in a sealed segment, or pointed by the index, doesn't match its checksum.
+    #[fail(display = "Corrupted record in segment {} at {}", gen, pos)]
+    Corrupted {
+        /// The generation of the segment
+        gen: u64,
+        /// The offset of the record in the segment
+        pos: u64,
+    },
+    /// A segment doesn't start with the file header, so it's not written by this crate.
+    #[fail(display = "Invalid header in segment {}", gen)]
+    InvalidHeader {
+        /// The generation of the segment
+        gen: u64,
+    },
+    /// A segment is written in a newer format than this crate supports.
+    #[fail(display = "Unsupported format version {} in segment {}", version, gen)]
+    UnsupportedVersion {
+        /// The generation of the segment
+        gen: u64,
+        /// The format version in the file header
+        version: u32,
+    },
+    /// A record can't be decoded although its checksum matches, or a message
+    /// between a client and a server can't be decoded.
+    #[fail(display = "Invalid record: {}", _0)]
+    InvalidRecord(#[cause] bincode::Error),
+    /// A possible error value when converting a String from the file data.
+    #[fail(display = "Invalid file data: {}", _0)]
+    InvalidUtf8(#[cause] std::string::FromUtf8Error),
+    /// The server failed to handle a request, or answered it with something
+    /// unexpected.
+    #[fail(display = "Server error: {}", message)]
+    Server {
+        /// The error displayed by the server
+        message: String,
+    },
+    /// A thread pool can't be created.
+    #[fail(display = "Thread pool error: {}", message)]
+    ThreadPool {
+        /// Why the pool can't be created
+        message: String,
+    },
+    /// There is a io::Error during the operation
+    #[fail(display = "{}", _0)]
+    Io(#[cause] std::io::Error),
+}
+
+impl From<std::io::Error> for KvsError {
+    fn from(err: std::io::Error) -> Self {
+        KvsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> Self {
+        KvsError::InvalidFile(err)
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> Self {
+        KvsError::InvalidRecord(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvsError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        KvsError::InvalidUtf8(err)
+    }
+}
+
+/// A specialized Result type for I/O operations.
+pub type Result<T> = std::result::Result<T, KvsError>;
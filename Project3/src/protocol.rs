@@ -0,0 +1,80 @@
+//! The protocol between `KvsClient` and `KvsServer`.
+//!
+//! A client sends requests over a TCP connection and the server answers each of
+//! them in order, until the client closes the connection. Every message is
+//! framed like a log record: the length of the payload as a little endian u32,
+//! followed by the payload, which is a `Request` or a `Response` encoded by
+//! bincode.
+
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// A request from the client.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Request {
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+/// The response of the server to a request.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Response {
+    /// The value of a `Get`.
+    Value(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    /// A `Set` or a `Remove` is done.
+    Done,
+    /// The key of a `Remove` doesn't exist.
+    KeyNotFound,
+    /// Any other error, as it's displayed.
+    Error(String),
+}
+
+/// Write a message as a frame.
+pub(crate) fn write_message(writer: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    let payload = bincode::serialize(message)?;
+    if payload.len() > u32::MAX as usize {
+        return Err(KvsError::ValueTooLarge {
+            size: payload.len(),
+            max: u32::MAX as usize,
+        });
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a message, or None if the connection is closed before it.
+pub(crate) fn read_message<T>(reader: &mut impl Read) -> Result<Option<T>>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len);
+    // The buffer grows as the payload comes in, a bogus length doesn't
+    // allocate anything up front.
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(bincode::deserialize(&payload)?))
+}
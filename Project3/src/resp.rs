@@ -0,0 +1,504 @@
+//! A front-end speaking RESP2, the protocol of Redis, so `redis-cli` and the
+//! Redis client libraries can use a `KvStore`.
+//!
+//! A command is an array of bulk strings, or an inline command: a line of words
+//! separated by spaces. The replies are simple strings, errors, integers, bulk
+//! strings and arrays. Only the commands below are supported, with the same
+//! replies as Redis:
+//!
+//! - `PING [MESSAGE]`
+//! - `GET key`
+//! - `SET key value [EX seconds | PX milliseconds]`
+//! - `DEL key [key ...]`
+//! - `EXISTS key [key ...]`
+//! - `KEYS pattern`
+//! - `SCAN cursor [MATCH pattern] [COUNT count]`
+//! - `EXPIRE key seconds`
+
+use crate::server::accept_loop;
+use crate::thread_pool::ThreadPool;
+use crate::{KvStore, KvsEngine, KvsError, Result};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The longest inline command, or line before a bulk string.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// The most arguments of a command.
+const MAX_ARGS: i64 = 1024 * 1024;
+
+/// The longest argument of a command.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+/// How many keys `SCAN` looks at without a `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// The most `SCAN` cursors the server keeps, the oldest ones are forgotten.
+const MAX_CURSORS: usize = 16 * 1024;
+
+/// Serves a `KvStore` to Redis clients over TCP. See the module documentation
+/// of `resp` for the supported commands.
+///
+/// Each connection is served by a job on the thread pool, like `KvsServer`
+/// does, so a pool of a fixed number of threads serves at most that many
+/// clients at once. The client libraries keep their connections open in a
+/// pool, `NaiveThreadPool` serves any number of them.
+#[derive(Debug)]
+pub struct RespServer<P: ThreadPool> {
+    store: KvStore,
+    pool: P,
+    cursors: Arc<Mutex<Cursors>>,
+}
+
+/// A reply to a command.
+#[derive(Debug)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// The cursors of the running `SCAN`s, by the number handed to the client.
+/// Each of them is the last key returned. They're shared by the connections,
+/// as the client libraries run the commands on any connection of their pool.
+///
+/// An abandoned scan is never ended, so only the last `MAX_CURSORS` cursors are
+/// kept.
+#[derive(Debug, Default)]
+struct Cursors {
+    last_keys: BTreeMap<u64, Vec<u8>>,
+    last_cursor: u64,
+}
+
+impl Cursors {
+    /// Hand out a cursor for a scan which returned up to `last_key`.
+    fn insert(&mut self, last_key: Vec<u8>) -> u64 {
+        if self.last_keys.len() >= MAX_CURSORS {
+            self.last_keys.pop_first();
+        }
+        self.last_cursor += 1;
+        self.last_keys.insert(self.last_cursor, last_key);
+        self.last_cursor
+    }
+
+    /// The last key returned before `cursor`, which is forgotten.
+    fn remove(&mut self, cursor: u64) -> Option<Vec<u8>> {
+        self.last_keys.remove(&cursor)
+    }
+}
+
+/// The state of a connection.
+struct Connection {
+    store: KvStore,
+    cursors: Arc<Mutex<Cursors>>,
+}
+
+impl<P: ThreadPool> RespServer<P> {
+    /// Creates a server of the store, serving the connections on the pool.
+    pub fn new(store: KvStore, pool: P) -> Self {
+        RespServer {
+            store,
+            pool,
+            cursors: Arc::default(),
+        }
+    }
+
+    /// Listen on `addr` and serve the connections. Returns only if binding
+    /// `addr` fails.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serve the connections of a listener. A connection which can't be
+    /// accepted is logged and skipped, like `KvsServer` does.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let (store, cursors) = (self.store, self.cursors);
+        accept_loop(listener, &self.pool, move |stream| {
+            serve_connection(Connection { store, cursors }, stream)
+        });
+        Ok(())
+    }
+}
+
+/// Answer the commands on a connection until the client closes it, or it sends
+/// something which isn't RESP.
+fn serve_connection(mut connection: Connection, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        match read_command(&mut reader) {
+            Ok(Some(args)) if args.is_empty() => (),
+            Ok(Some(args)) => write_reply(&mut writer, &connection.execute(args))?,
+            Ok(None) => return Ok(()),
+            Err(KvsError::InvalidCommand { command }) => {
+                let reply = Reply::Error(format!("ERR Protocol error: {}", command));
+                write_reply(&mut writer, &reply)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+        // The pipelined commands are answered together.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+impl Connection {
+    fn execute(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+        let result = match (name.as_str(), args.len()) {
+            ("ping", 1) => Ok(Reply::Status("PONG")),
+            ("ping", 2) => Ok(Reply::Bulk(args.into_iter().nth(1))),
+            ("get", 2) => self.store.get_bytes(&args[1]).map(Reply::Bulk),
+            ("set", n) if n >= 3 => self.set(args),
+            ("del", n) if n >= 2 => self.del(&args[1..]),
+            ("exists", n) if n >= 2 => self.exists(&args[1..]),
+            ("keys", 2) => Ok(self.keys(&args[1])),
+            ("scan", n) if n >= 2 => Ok(self.scan(&args[1..])),
+            ("expire", 3) => self.expire(&args[1], &args[2]),
+            ("ping", _)
+            | ("get", _)
+            | ("set", _)
+            | ("del", _)
+            | ("exists", _)
+            | ("keys", _)
+            | ("scan", _)
+            | ("expire", _) => Ok(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ))),
+            _ => Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+        };
+        result.unwrap_or_else(|err| match err {
+            KvsError::ReadOnly => Reply::Error(format!("READONLY {}", err)),
+            err => Reply::Error(format!("ERR {}", err)),
+        })
+    }
+
+    fn set(&self, args: Vec<Vec<u8>>) -> Result<Reply> {
+        let mut args = args.into_iter().skip(1);
+        let (key, value) = (args.next().unwrap(), args.next().unwrap());
+        let ttl = match (args.next(), args.next(), args.next()) {
+            (None, ..) => None,
+            (Some(unit), Some(ttl), None) => {
+                let ttl = match parse_int(&ttl) {
+                    Some(ttl) if ttl > 0 => ttl as u64,
+                    _ => return Ok(invalid_expire_time("set")),
+                };
+                match unit.to_ascii_lowercase().as_slice() {
+                    b"ex" => Some(Duration::from_secs(ttl)),
+                    b"px" => Some(Duration::from_millis(ttl)),
+                    _ => return Ok(syntax_error()),
+                }
+            }
+            _ => return Ok(syntax_error()),
+        };
+        match ttl {
+            Some(ttl) => self.store.set_bytes_with_ttl(key, value, ttl)?,
+            None => self.store.set_bytes(key, value)?,
+        }
+        Ok(Reply::Status("OK"))
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> Result<Reply> {
+        let mut removed = 0;
+        for key in keys {
+            match self.store.remove_bytes(key) {
+                Ok(()) => removed += 1,
+                Err(KvsError::KeyNotFound) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Reply::Integer(removed))
+    }
+
+    fn exists(&self, keys: &[Vec<u8>]) -> Result<Reply> {
+        let mut found = 0;
+        for key in keys {
+            if self.store.get_bytes(key)?.is_some() {
+                found += 1;
+            }
+        }
+        Ok(Reply::Integer(found))
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Reply {
+        let keys = self
+            .store
+            .scan_prefix_bytes(glob_prefix(pattern).to_vec())
+            .keys()
+            .filter(|key| glob_match(pattern, key))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Reply::Array(keys)
+    }
+
+    /// Look at `COUNT` keys after the cursor, and return the ones matching the
+    /// pattern. The cursor of the last call is forgotten, so a scan which isn't
+    /// run to the end only holds a key until `MAX_CURSORS` newer ones.
+    fn scan(&self, args: &[Vec<u8>]) -> Reply {
+        let start = match parse_int(&args[0]) {
+            Some(0) => Bound::Unbounded,
+            Some(cursor) => match self.cursors.lock().unwrap().remove(cursor as u64) {
+                Some(last) => Bound::Excluded(last),
+                None => return Reply::Error("ERR invalid cursor".to_owned()),
+            },
+            None => return Reply::Error("ERR invalid cursor".to_owned()),
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            match (option[0].to_ascii_lowercase().as_slice(), option.get(1)) {
+                (b"match", Some(arg)) => pattern = Some(arg.as_slice()),
+                (b"count", Some(arg)) => match parse_int(arg) {
+                    Some(arg) if arg > 0 => count = arg as usize,
+                    _ => return syntax_error(),
+                },
+                _ => return syntax_error(),
+            }
+        }
+
+        let keys: Vec<Vec<u8>> = self
+            .store
+            .scan_bytes((start, Bound::Unbounded))
+            .keys()
+            .take(count)
+            .collect();
+        let cursor = match keys.last() {
+            Some(last) if keys.len() == count => self.cursors.lock().unwrap().insert(last.clone()),
+            _ => 0,
+        };
+        let keys = keys
+            .into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Reply::Array(vec![
+            Reply::Bulk(Some(cursor.to_string().into_bytes())),
+            Reply::Array(keys),
+        ])
+    }
+
+    /// A key expiring in no time is removed, like Redis does.
+    fn expire(&self, key: &[u8], seconds: &[u8]) -> Result<Reply> {
+        let result = match parse_int(seconds) {
+            Some(seconds) if seconds <= 0 => self.store.remove_bytes(key),
+            Some(seconds) => self
+                .store
+                .expire_bytes(key, Duration::from_secs(seconds as u64)),
+            None => return Ok(not_an_integer()),
+        };
+        match result {
+            Ok(()) => Ok(Reply::Integer(1)),
+            Err(KvsError::KeyNotFound) => Ok(Reply::Integer(0)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn invalid_expire_time(command: &str) -> Reply {
+    Reply::Error(format!("ERR invalid expire time in '{}' command", command))
+}
+
+/// Read a command, or None if the connection is closed before it. An empty
+/// command is skipped by the caller, like Redis does. Anything which isn't RESP
+/// is `KvsError::InvalidCommand`.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with(b"*") {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = match parse_int(&line[1..]) {
+        Some(count) if count <= MAX_ARGS => count,
+        _ => return Err(protocol_error("invalid multibulk length")),
+    };
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if !line.starts_with(b"$") {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = match parse_int(&line[1..]) {
+            Some(len) if (0..=MAX_BULK_LEN).contains(&len) => len as u64,
+            _ => return Err(protocol_error("invalid bulk length")),
+        };
+        // The buffer grows as the argument comes in, a bogus length doesn't
+        // allocate anything up front.
+        let mut arg = Vec::new();
+        reader.take(len).read_to_end(&mut arg)?;
+        let mut end = [0; 2];
+        reader.read_exact(&mut end)?;
+        if arg.len() as u64 != len || &end != b"\r\n" {
+            return Err(protocol_error("invalid bulk string"));
+        }
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line ending with `\r\n` or `\n`, without the line ending.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return match line.len() as u64 {
+            MAX_LINE => Err(protocol_error("too big inline request")),
+            _ => Err(unexpected_eof()),
+        };
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::InvalidCommand {
+        command: message.to_owned(),
+    }
+}
+
+fn unexpected_eof() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+fn parse_int(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn write_reply(writer: &mut impl Write, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Status(status) => write!(writer, "+{}\r\n", status),
+        // An error is a single line.
+        Reply::Error(error) => write!(writer, "-{}\r\n", error.replace(['\r', '\n'], " ")),
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+        Reply::Bulk(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")
+        }
+        Reply::Array(replies) => {
+            write!(writer, "*{}\r\n", replies.len())?;
+            for reply in replies {
+                write_reply(writer, reply)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The literal start of a glob pattern, which all the matching keys start with.
+fn glob_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| b"*?[\\".contains(b))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Whether a key matches a glob pattern of `KEYS`: `*` matches anything, `?`
+/// matches a byte, `[abc]`, `[^abc]` and `[a-z]` match a byte in a set, and `\`
+/// escapes the next byte.
+///
+/// It backtracks to the last `*` only, a later `*` matches whatever an earlier one
+/// would. So it takes at most the length of the pattern times the length of the
+/// key, however many `*` there are.
+fn glob_match(mut pattern: &[u8], mut key: &[u8]) -> bool {
+    // The pattern after the last `*`, and the key from where it stopped.
+    let mut star: Option<(&[u8], &[u8])> = None;
+    loop {
+        let rest = match pattern {
+            [b'*', rest @ ..] => {
+                star = Some((rest, key));
+                pattern = rest;
+                continue;
+            }
+            [] if key.is_empty() => return true,
+            [] => None,
+            [b'?', rest @ ..] => Some(rest).filter(|_| !key.is_empty()),
+            [b'[', class @ ..] => key.first().and_then(|&b| match match_class(class, b) {
+                (true, rest) => Some(rest),
+                (false, _) => None,
+            }),
+            [b'\\', escaped, rest @ ..] | [escaped, rest @ ..] => {
+                Some(rest).filter(|_| key.first() == Some(escaped))
+            }
+        };
+        match (rest, star) {
+            (Some(rest), _) => {
+                pattern = rest;
+                key = &key[1..];
+            }
+            // Let the last `*` match one more byte.
+            (None, Some((after_star, [_, skipped @ ..]))) => {
+                star = Some((after_star, skipped));
+                pattern = after_star;
+                key = skipped;
+            }
+            (None, _) => return false,
+        }
+    }
+}
+
+/// Whether a byte is in the set at the start of `class`, which is the pattern
+/// after a `[`. Returns the pattern after the `]` too.
+fn match_class(class: &[u8], b: u8) -> (bool, &[u8]) {
+    let (negated, mut class) = match class {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    loop {
+        class = match class {
+            // An unclosed set ends with the pattern.
+            [] => break,
+            [b']', rest @ ..] => {
+                class = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == b;
+                rest
+            }
+            [low, b'-', high, rest @ ..] if *high != b']' => {
+                matched |= (*low.min(high)..=*low.max(high)).contains(&b);
+                rest
+            }
+            [other, rest @ ..] => {
+                matched |= *other == b;
+                rest
+            }
+        };
+    }
+    (matched != negated, class)
+}
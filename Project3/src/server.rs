@@ -0,0 +1,102 @@
+use crate::protocol::{read_message, write_message, Request, Response};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsEngine, KvsError, Result};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// The address `kvs-server` listens on, and `kvs-client` connects to, by
+/// default.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+/// Serves a `KvsEngine` over TCP to `KvsClient`s. A client sends requests on a
+/// connection, and the server answers each of them in order.
+///
+/// Each connection is served by a job on the thread pool, which holds a thread
+/// until the client closes the connection. So a pool of a fixed number of
+/// threads serves at most that many clients at once, the others wait until one
+/// of them leaves, even while it's idle. `NaiveThreadPool` serves any number
+/// of them, with a thread each.
+#[derive(Debug)]
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Creates a server of the engine, serving the connections on the pool.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer { engine, pool }
+    }
+
+    /// Listen on `addr` and serve the connections. Returns only if binding
+    /// `addr` fails.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serve the connections of a listener. An error accepting a connection,
+    /// e.g. the process is out of file descriptors, is logged and skipped.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let engine = self.engine;
+        accept_loop(listener, &self.pool, move |stream| {
+            serve_connection(&engine, stream)
+        });
+        Ok(())
+    }
+}
+
+/// How long to wait after failing to accept a connection, so running out of
+/// file descriptors doesn't spin the loop.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Accept the connections of a listener forever, serving each of them by a job
+/// on the pool. Accepting fails on transient errors, e.g. the client reset the
+/// connection already, or the process is out of file descriptors, so the error
+/// is logged and the next connection is accepted after a short delay.
+pub(crate) fn accept_loop<P, F>(listener: TcpListener, pool: &P, serve: F)
+where
+    P: ThreadPool,
+    F: FnOnce(TcpStream) -> Result<()> + Clone + Send + 'static,
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Error on accepting a connection: {}", err);
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+        };
+        let serve = serve.clone();
+        pool.spawn(move || {
+            if let Err(err) = serve(stream) {
+                eprintln!("Error on serving client: {}", err);
+            }
+        });
+    }
+}
+
+/// Answer the requests on a connection until the client closes it.
+fn serve_connection(engine: &impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    while let Some(request) = read_message(&mut reader)? {
+        write_message(&mut writer, &handle(engine, request))?;
+    }
+    Ok(())
+}
+
+fn handle(engine: &impl KvsEngine, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get_bytes(&key).map(Response::Value),
+        Request::Set { key, value } => engine.set_bytes(key, value).map(|_| Response::Done),
+        Request::Remove { key } => engine.remove_bytes(&key).map(|_| Response::Done),
+    };
+    match result {
+        Ok(response) => response,
+        Err(KvsError::KeyNotFound) => Response::KeyNotFound,
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
@@ -0,0 +1,28 @@
+//! The thread pools running the jobs of the servers. Every pool implements
+//! `ThreadPool`, so the server can pick one at startup.
+
+use crate::Result;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon_pool::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod rayon_pool;
+mod shared_queue;
+
+/// The interface of a thread pool.
+pub trait ThreadPool: Send + Sync + 'static {
+    /// Creates a pool of `threads` threads.
+    ///
+    /// Return an error if the threads can't be created.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Run a job on a thread of the pool. A job which panics doesn't take the
+    /// pool down.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,22 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+/// Not a pool at all: it spawns a new thread for every job. It's the baseline
+/// the other pools are compared with.
+#[derive(Debug)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    /// The number of threads is ignored.
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
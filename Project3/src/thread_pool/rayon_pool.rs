@@ -0,0 +1,28 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+/// A pool backed by the work stealing pool of rayon.
+#[derive(Debug)]
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .build()
+            .map_err(|err| KvsError::ThreadPool {
+                message: err.to_string(),
+            })?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // rayon aborts the process if a spawned job panics.
+        self.0.spawn(move || {
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+        })
+    }
+}
@@ -0,0 +1,77 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads, which take the jobs off a shared queue. A job
+/// which panics is caught, and the thread goes on with the next job.
+///
+/// Dropping the pool waits for the jobs already spawned.
+#[derive(Debug)]
+pub struct SharedQueueThreadPool {
+    // Only taken when the pool is dropped.
+    sender: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Return an error if `threads` is 0.
+    fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::ThreadPool {
+                message: "A pool needs at least one thread".to_owned(),
+            });
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("kvs-pool-{}", i))
+                    .spawn(move || run_jobs(&receiver))
+                    .map_err(KvsError::from)
+            })
+            .collect::<Result<_>>()?;
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            threads,
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = self.sender.as_ref().expect("the pool is shut down");
+        // The threads only stop once the sender is dropped.
+        sender.send(Box::new(job)).unwrap();
+    }
+}
+
+/// Run the jobs until the pool is dropped.
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for thread in self.threads.drain(..) {
+            // The last handle of the pool may be dropped by one of its jobs.
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
@@ -1,8 +1,17 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvStore, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, LogFile, MemStore, Options,
+    RespServer, Result, SyncPolicy, WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use proptest::prelude::*;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +26,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +37,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +50,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,25 +63,67 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 }
 
+// `kvs set <KEY> <VALUE> --ttl <TTL>` should expire the key.
+#[test]
+fn cli_set_ttl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "500ms"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2", "--ttl", "1h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    std::thread::sleep(Duration::from_millis(600));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key3", "value3", "--ttl", "10y"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +131,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,26 +145,419 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+// `kvs batch <FILE>` should apply all the operations in the file.
+#[test]
+fn cli_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
+    std::fs::write(
+        temp_dir.path().join("batch.txt"),
+        "# move key1\nrm key1\n\nset key2 value with spaces\nset key3 value3\n",
+    )?;
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["batch", "batch.txt"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("value with spaces".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `kvs batch <FILE>` should apply nothing if a line is invalid.
+#[test]
+fn cli_batch_invalid() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("batch.txt"),
+        "set key1 value1\nput key2 value2\n",
+    )?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "batch.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(eq("Key not found").trim());
+    Ok(())
+}
+
+// `kvs backup` should copy the store into a directory or a tarball, and
+// `kvs restore` should copy it back into an empty directory.
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // The store is backed up while its writer holds it.
+    for name in ["backup", "backup.tar", "backup.tar.gz"] {
+        let backup = backup_dir.path().join(name);
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("backup")
+            .arg(&backup)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("Backed up 100 keys").trim());
+        assert!(backup.exists());
+
+        let restored = TempDir::new().expect("unable to create temporary working directory");
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("restore")
+            .arg(&backup)
+            .current_dir(&restored)
+            .assert()
+            .success()
+            .stdout(eq("Restored 100 keys").trim());
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key42"])
+            .current_dir(&restored)
+            .assert()
+            .success()
+            .stdout(eq("value42").trim());
+
+        // The directory holds a store now.
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("restore")
+            .arg(&backup)
+            .current_dir(&restored)
+            .assert()
+            .failure()
+            .stderr(contains("StoreExists"));
+    }
+    drop(store);
+
+    // The backup is never written over.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(backup_dir.path().join("backup.tar"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // A failed backup leaves nothing of its own behind, so it's retried.
+    let tarball = backup_dir.path().join("retried.tgz");
+    let part = backup_dir.path().join("retried.tgz.part");
+    std::fs::write(&part, b"not ours")?;
+    let backup = || {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("backup")
+            .arg(&tarball)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    backup().failure();
+    assert!(!tarball.exists());
+    assert!(!backup_dir.path().join("retried.tgz.tmp").exists());
+    assert_eq!(std::fs::read(&part)?, b"not ours");
+    std::fs::remove_file(&part)?;
+    backup().success().stdout(eq("Backed up 100 keys").trim());
+    assert!(tarball.exists() && !part.exists());
+
+    // A failed restore leaves nothing behind.
+    let corrupt = backup_dir.path().join("corrupt");
+    std::fs::create_dir(&corrupt)?;
+    std::fs::write(corrupt.join("1.log"), b"not a segment at all")?;
+    let restored = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&corrupt)
+        .current_dir(&restored)
+        .assert()
+        .failure()
+        .stderr(contains("InvalidHeader"));
+    assert_eq!(std::fs::read_dir(restored.path())?.count(), 0);
+
+    // Nor does it remove a staging directory it didn't create.
+    let staging = restored.path().join("restore.tmp");
+    std::fs::create_dir(&staging)?;
+    std::fs::write(staging.join("file"), b"not ours")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(backup_dir.path().join("backup"))
+        .current_dir(&restored)
+        .assert()
+        .failure();
+    assert_eq!(std::fs::read(staging.join("file"))?, b"not ours");
+    Ok(())
+}
+
+// `kvs export` should print every live key and its value, and `kvs import`
+// should set them all in another store, with a single compaction.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set(
+        "key2".to_owned(),
+        "a \"quoted\", value\nover two lines".to_owned(),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    drop(store);
+    thread::sleep(Duration::from_millis(10));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(concat!(
+            "{\"key\":\"key1\",\"value\":\"value1\"}\n",
+            "{\"key\":\"key2\",\"value\":\"a \\\"quoted\\\", value\\nover two lines\"}\n",
+        )));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(concat!(
+            "key,value\n",
+            "key1,value1\n",
+            "key2,\"a \"\"quoted\"\", value\nover two lines\"\n",
+        )));
+
+    for (format, file) in [("jsonl", "dump.jsonl"), ("csv", "dump.csv")] {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["export", "--format", format])
+            .current_dir(&temp_dir)
+            .output()?;
+        let imported = TempDir::new().expect("unable to create temporary working directory");
+        std::fs::write(imported.path().join(file), output.stdout)?;
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["import", "--format", format, file])
+            .current_dir(&imported)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        assert_eq!(log_files(imported.path()), vec!["2.log", "3.log"]);
+        let store = KvStore::open(imported.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(
+            store.get("key2".to_owned())?,
+            Some("a \"quoted\", value\nover two lines".to_owned())
+        );
+        assert_eq!(store.scan(..).keys().count(), 2);
+    }
+    Ok(())
+}
+
+// `kvs export` and `kvs import` should encode the binary keys and values, and
+// the import should set nothing if a record is invalid.
+#[test]
+fn cli_export_import_binary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(vec![0xff, 0x00], vec![0x01, 0xfe])?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output-format", "hex"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key,value\nff00,01fe\n"));
 
+    let imported = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        imported.path().join("dump.jsonl"),
+        "{\"key\":\"/wA=\",\"value\":\"Af4=\"}\n{\"key\":\"a2V5\",\"value\":\"dmFsdWU=\"}\n",
+    )?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "dump.jsonl", "--input-format", "base64"])
+        .current_dir(&imported)
+        .assert()
+        .success();
+    let store = KvStore::open(imported.path())?;
+    assert_eq!(store.get_bytes(&[0xff, 0x00])?, Some(vec![0x01, 0xfe]));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    std::fs::write(
+        imported.path().join("dump.csv"),
+        "key,value\nkey1,value1\nkey2\n",
+    )?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv", "dump.csv"])
+        .current_dir(&imported)
+        .assert()
+        .failure();
+    let store = KvStore::open(imported.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs scan` and `kvs keys` should print the keys in order.
+#[test]
+fn cli_scan_and_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:2:name", "user:1:name", "user:1:mail", "group:1"] {
+        store.set(key.to_string(), format!("{} value", key))?;
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "user:1:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "user:1:mail user:1:mail value\nuser:1:name user:1:name value\n",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--from", "user:", "--to", "user:2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "user:1:mail user:1:mail value\nuser:1:name user:1:name value\n",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("group:1\nuser:1:mail\nuser:1:name\nuser:2:name\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "user:2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:2:name\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--from", "a"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// `--input-format` and `--output-format` should encode the keys and values in
+// hex or base64.
+#[test]
+fn cli_binary_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--input-format", "hex", "set", "00ff", "c328"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "get",
+            "AP8=",
+            "--input-format",
+            "base64",
+            "--output-format",
+            "hex",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("c328").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "--output-format", "base64"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("AP8=\n"));
+
+    // The value isn't text.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--input-format", "hex", "get", "00ff"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--input-format", "hex", "get", "zz"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&[0x00, 0xff])?, Some(vec![0xc3, 0x28]));
     Ok(())
 }
 
@@ -121,13 +565,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +580,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +601,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +616,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -181,7 +625,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +635,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +646,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +655,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +667,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +683,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +691,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,7 +703,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -289,7 +733,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +743,2380 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Runs the same scenario against any engine.
+fn engine_set_get_remove(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    match engine.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        other => panic!("expect KeyNotFound, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn kv_store_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_set_get_remove(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn mem_store_engine() -> Result<()> {
+    engine_set_get_remove(MemStore::new())
+}
+
+fn engine_binary_keys_and_values(engine: impl KvsEngine) -> Result<()> {
+    let key = vec![0x00, 0xff, 0xfe];
+    let value = vec![0xc3, 0x28, 0x00, 0x01];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(&key)?, Some(value));
+    assert_eq!(engine.get_bytes(&[0x00])?, None);
+
+    engine.set_bytes(b"key1".to_vec(), vec![0xff])?;
+    match engine.get("key1".to_owned()) {
+        Err(KvsError::InvalidUtf8(_)) => (),
+        other => panic!("expect InvalidUtf8, got {:?}", other),
+    }
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get_bytes(b"key2")?, Some(b"value2".to_vec()));
+
+    engine.remove_bytes(&key)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    match engine.remove_bytes(&key) {
+        Err(KvsError::KeyNotFound) => (),
+        other => panic!("expect KeyNotFound, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn kv_store_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_binary_keys_and_values(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn mem_store_binary_keys_and_values() -> Result<()> {
+    engine_binary_keys_and_values(MemStore::new())
+}
+
+// The segment files in the directory, ordered by generation.
+fn log_files(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .expect("fail to read directory")
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort_by_key(|name| name.trim_end_matches(".log").parse::<u64>().unwrap());
+    names
+}
+
+// The log is split into several segments once it exceeds the segment size,
+// and all of them are replayed when opening the store again.
+#[test]
+fn segments_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_files(temp_dir.path()).len() > 1);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// A `kvs.db` written by the single file format is taken as the first segment.
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("kvs.db"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key1"}}"#,
+    )?;
+
+    // It's only upgraded by a writer.
+    match KvStore::open_read_only(temp_dir.path()) {
+        Err(KvsError::UpgradeRequired) => (),
+        other => panic!("expect UpgradeRequired, got {:?}", other),
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("kvs.db").exists());
+    assert_eq!(log_files(temp_dir.path()), vec!["1.log".to_owned()]);
+    let data = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert_eq!(&data[..8], b"KVS\0\x03\0\0\0");
+
+    // Open the upgraded store again
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A `kvs.db` torn by a power loss is upgraded up to its last complete operation.
+#[test]
+fn open_torn_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let torn = r#"{"Set":{"key":"key2","val"#;
+    std::fs::write(
+        temp_dir.path().join("kvs.db"),
+        format!("{}{}", r#"{"Set":{"key":"key1","value":"value1"}}"#, torn),
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), torn.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(!temp_dir.path().join("kvs.db").exists());
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Whitespace after the last operation isn't a torn tail.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("kvs.db"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+    for entry in std::fs::read_dir(from).expect("fail to read directory") {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), to.join(entry.file_name())).expect("fail to copy file");
+    }
+}
+
+// The directories right before and right after a compaction. The compaction is
+// triggered by setting the "big" key, whose value (if any) in each state is
+// also returned.
+struct CompactionStates {
+    before: TempDir,
+    before_big: String,
+    after: TempDir,
+    after_big: String,
+}
+
+fn compaction_states() -> Result<CompactionStates> {
+    let after = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(after.path(), Options::new().segment_size(256 * 1024))?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut before_big = String::new();
+    for iter in 0..100 {
+        let before = TempDir::new().expect("unable to create temporary working directory");
+        copy_dir(after.path(), before.path());
+        let oldest = log_files(before.path())[0].clone();
+
+        let big = format!("{}{}", iter, "x".repeat(100 * 1024));
+        store.set("big".to_owned(), big.clone())?;
+        store.wait_for_compaction()?;
+        if !log_files(after.path()).contains(&oldest) {
+            // Compaction triggered
+            return Ok(CompactionStates {
+                before,
+                before_big,
+                after,
+                after_big: big,
+            });
+        }
+        before_big = big;
+    }
+    panic!("No compaction detected");
+}
+
+fn check_content(store: &KvStore, big: &str) -> Result<()> {
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("big".to_owned())?.as_deref(), Some(big));
+    Ok(())
+}
+
+// Crash while writing the compacted records into the temporary file.
+#[test]
+fn compaction_crash_writing_temp_file() -> Result<()> {
+    let states = compaction_states()?;
+    let compacted = &log_files(states.after.path())[0];
+    let data = std::fs::read(states.after.path().join(compacted))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.before.path(), temp_dir.path());
+    let temp_file = temp_dir.path().join(format!("{}.tmp", compacted));
+    std::fs::write(&temp_file, &data[..data.len() / 2])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_content(&store, &states.before_big)?;
+    assert!(!temp_file.exists());
+    Ok(())
+}
+
+// Crash after the temporary file is written, but before it's renamed.
+#[test]
+fn compaction_crash_before_rename() -> Result<()> {
+    let states = compaction_states()?;
+    let files = log_files(states.after.path());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.before.path(), temp_dir.path());
+    let temp_file = temp_dir.path().join(format!("{}.tmp", files[0]));
+    std::fs::copy(states.after.path().join(&files[0]), &temp_file)?;
+    std::fs::copy(
+        states.after.path().join(&files[1]),
+        temp_dir.path().join(&files[1]),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_content(&store, &states.before_big)?;
+    assert!(!temp_file.exists());
+    Ok(())
+}
+
+// Crash after the compacted segment is renamed in place, but before any
+// compacted segment is removed.
+#[test]
+fn compaction_crash_before_removing_segments() -> Result<()> {
+    let states = compaction_states()?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.before.path(), temp_dir.path());
+    copy_dir(states.after.path(), temp_dir.path());
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_content(&store, &states.after_big)?;
+    assert_eq!(log_files(temp_dir.path()), log_files(states.after.path()));
+    Ok(())
+}
+
+// Crash after some of the compacted segments are removed.
+#[test]
+fn compaction_crash_removing_segments() -> Result<()> {
+    let states = compaction_states()?;
+    let before_files = log_files(states.before.path());
+    assert!(before_files.len() > 2);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.after.path(), temp_dir.path());
+    for file in &before_files[before_files.len() / 2..] {
+        std::fs::copy(states.before.path().join(file), temp_dir.path().join(file))?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_content(&store, &states.after_big)?;
+    assert_eq!(log_files(temp_dir.path()), log_files(states.after.path()));
+    Ok(())
+}
+
+fn append_to(path: &std::path::Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+// Remove the hint file written when the store was closed, so the whole log is
+// replayed, like after a crash.
+fn remove_hint(dir: &std::path::Path) -> Result<()> {
+    std::fs::remove_file(dir.join("kvs.hint"))?;
+    Ok(())
+}
+
+// A half written record at the end of the log is truncated away when opening.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir
+        .path()
+        .join(log_files(temp_dir.path()).pop().unwrap());
+    let len = std::fs::metadata(&segment)?.len();
+    append_to(&segment, &[30, 0, 0, 0, 1, 2, 3, 4, b'{'])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 9);
+    assert_eq!(std::fs::metadata(&segment)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record whose checksum mismatches at the end of the log is truncated away.
+#[test]
+fn recover_corrupt_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let segment = temp_dir
+        .path()
+        .join(log_files(temp_dir.path()).pop().unwrap());
+    let len = std::fs::metadata(&segment)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut data = std::fs::read(&segment)?;
+    let last = data.len() - 3;
+    data[last] ^= 0xff;
+    std::fs::write(&segment, &data)?;
+    remove_hint(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), data.len() as u64 - len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A corrupt record in a sealed segment can't be recovered from.
+#[test]
+fn corrupt_sealed_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let segment = temp_dir.path().join(&log_files(temp_dir.path())[0]);
+    let mut data = std::fs::read(&segment)?;
+    data[20] ^= 0xff;
+    std::fs::write(&segment, &data)?;
+    remove_hint(temp_dir.path())?;
+
+    match KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024)) {
+        Err(KvsError::Corrupted { pos: 8, .. }) => Ok(()),
+        other => panic!("expect Corrupted, got {:?}", other.map(|_| ())),
+    }
+}
+
+// The index is saved as a hint file when the store is closed, and opening it
+// again only replays the log written after the hint.
+#[test]
+fn hint_file_skips_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("kvs.hint").exists());
+
+    // The stale record of key0 is never read with the hint.
+    let segment = temp_dir.path().join(&log_files(temp_dir.path())[0]);
+    let mut data = std::fs::read(&segment)?;
+    data[20] ^= 0xff;
+    std::fs::write(&segment, &data)?;
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    remove_hint(temp_dir.path())?;
+    match KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024)) {
+        Err(KvsError::Corrupted { pos: 8, .. }) => Ok(()),
+        other => panic!("expect Corrupted, got {:?}", other.map(|_| ())),
+    }
+}
+
+// The writes after the hint file, and the compactions, survive a crash which
+// leaves no hint behind.
+#[test]
+fn hint_file_replays_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value0".to_owned())?;
+    }
+    drop(store);
+
+    // The copies of the directory while the store is open are left by a crash.
+    let crashed = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    store.remove("key99".to_owned())?;
+    copy_dir(temp_dir.path(), crashed.path());
+    drop(store);
+
+    let crashed_again = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(crashed.path(), Options::new().segment_size(1024))?;
+    store.compact()?;
+    store.set("key0".to_owned(), "value2".to_owned())?;
+    store.remove("key98".to_owned())?;
+    copy_dir(crashed.path(), crashed_again.path());
+    drop(store);
+
+    let store = KvStore::open_with(crashed_again.path(), Options::new().segment_size(1024))?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value2".to_owned()));
+    for key_id in 1..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value1".to_owned())
+        );
+    }
+    for key_id in 50..98 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value0".to_owned())
+        );
+    }
+    assert_eq!(store.get("key98".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, None);
+    Ok(())
+}
+
+// A hint file which is corrupt, or whose segments were compacted away since,
+// is ignored and the whole log is replayed.
+#[test]
+fn stale_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hint = temp_dir.path().join("kvs.hint");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let old_hint = std::fs::read(&hint)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    std::fs::write(&hint, &old_hint)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let mut data = std::fs::read(&hint)?;
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    std::fs::write(&hint, &data)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Every segment starts with the magic number and the format version.
+#[test]
+fn segment_file_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    for file in log_files(temp_dir.path()) {
+        let data = std::fs::read(temp_dir.path().join(file))?;
+        assert_eq!(&data[..8], b"KVS\0\x03\0\0\0");
+    }
+    Ok(())
+}
+
+#[test]
+fn open_invalid_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), b"not a segment")?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::InvalidHeader { gen: 1 }) => (),
+        other => panic!("expect InvalidHeader, got {:?}", other.map(|_| ())),
+    }
+
+    std::fs::write(temp_dir.path().join("1.log"), b"KVS\0\x63\0\0\0")?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedVersion {
+            gen: 1,
+            version: 99,
+        }) => Ok(()),
+        other => panic!("expect UnsupportedVersion, got {:?}", other.map(|_| ())),
+    }
+}
+
+// The active segment can be torn right after it's created.
+#[test]
+fn recover_torn_file_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::write(temp_dir.path().join("100.log"), b"KVS")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Several threads write through clones of the same store while others read,
+// and the writes keep triggering compactions. A reader never sees a value
+// older than one it has seen before.
+#[test]
+fn concurrent_readers_and_writers() -> Result<()> {
+    use std::collections::HashMap;
+
+    fn assert_send_sync<T: Clone + Send + Sync>() {}
+    assert_send_sync::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(64 * 1024))?;
+    let padding = "x".repeat(1000);
+
+    let mut writers = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let padding = padding.clone();
+        writers.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..10 {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key, format!("{} {}", iter, padding))?;
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    let mut readers = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        readers.push(thread::spawn(move || -> Result<()> {
+            let mut seen = HashMap::new();
+            for _ in 0..20 {
+                for thread_id in 0..4 {
+                    for key_id in 0..100 {
+                        let key = format!("key{}_{}", thread_id, key_id);
+                        if let Some(value) = store.get(key.clone())? {
+                            let iter: u32 = value.split(' ').next().unwrap().parse().unwrap();
+                            let last = seen.insert(key, iter).unwrap_or(0);
+                            assert!(iter >= last);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                let key = format!("key{}_{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, Some(format!("9 {}", padding)));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with(
+        temp_dir.path(),
+        Options::new().segment_size(64 * 1024),
+    )?)
+}
+
+// `compact` rewrites the log right away and only leaves the compacted segment
+// and the new active segment.
+#[test]
+fn explicit_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let files = log_files(temp_dir.path());
+    assert!(files.len() > 2);
+
+    store.compact()?;
+    let compacted = log_files(temp_dir.path());
+    assert_eq!(compacted.len(), 2);
+    let gen = |file: &String| file.trim_end_matches(".log").parse::<u64>().unwrap();
+    assert!(gen(&compacted[0]) > gen(&files[files.len() - 1]));
+
+    store.set("key0".to_owned(), "value10".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value10".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A checkpoint holds every write made before it and none made after, even
+// with writers and compactions going on, and its sealed segments are linked.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = Options::new();
+    options.segment_size(1024).compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 0..2000 {
+                store
+                    .set(format!("counter{}", iter), "value".to_owned())
+                    .unwrap();
+                store
+                    .set(format!("key{}", iter % 100), "value1".to_owned())
+                    .unwrap();
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(20));
+    let backup = backup_dir.path().join("backup");
+    store.checkpoint(&backup)?;
+    writer.join().unwrap();
+    store.set("key0".to_owned(), "value2".to_owned())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // Unless the store compacted its own link away since.
+        let name = &log_files(&backup)[0];
+        assert!(
+            std::fs::metadata(backup.join(name))?.nlink() > 1
+                || !temp_dir.path().join(name).exists()
+        );
+    }
+    let copy = KvStore::open_with(&backup, &options)?;
+    let counters = copy.scan_prefix("counter".to_owned()).keys().count();
+    for iter in 0..counters {
+        assert_eq!(
+            copy.get(format!("counter{}", iter))?,
+            Some("value".to_owned())
+        );
+    }
+    for key_id in 0..100 {
+        let value = copy.get(format!("key{}", key_id))?;
+        assert!(value == Some("value0".to_owned()) || value == Some("value1".to_owned()));
+    }
+    copy.set("key0".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value2".to_owned()));
+
+    // The writes to the copy never reach the segments of the store.
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(source_dir.path())?;
+    source.set("key".to_owned(), "value".to_owned())?;
+    let other = backup_dir.path().join("other");
+    source.checkpoint(&other)?;
+    let copy = KvStore::open(&other)?;
+    copy.set("leaked".to_owned(), "value".to_owned())?;
+    drop(copy);
+    drop(source);
+    remove_hint(source_dir.path())?;
+    let source = KvStore::open(source_dir.path())?;
+    assert_eq!(source.get("leaked".to_owned())?, None);
+    assert_eq!(source.scan(..).keys().count(), 1);
+    drop(source);
+
+    match store.checkpoint(&backup) {
+        Err(KvsError::Io(_)) => (),
+        other => panic!("expect Io, got {:?}", other),
+    }
+    Ok(())
+}
+
+// A store opened read only writes a checkpoint of what it loaded, next to the
+// writer compacting its segments away, and without the torn tail of the log.
+#[test]
+fn checkpoint_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = Options::new();
+    options.segment_size(1024).compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value0".to_owned())?;
+    }
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    store.compact()?;
+    let backup = backup_dir.path().join("backup");
+    reader.checkpoint(&backup)?;
+    drop(reader);
+
+    let copy = KvStore::open(&backup)?;
+    assert_eq!(copy.scan(..).keys().count(), 100);
+    for key_id in 0..100 {
+        assert_eq!(
+            copy.get(format!("key{}", key_id))?,
+            Some("value0".to_owned())
+        );
+    }
+    copy.set("key0".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value1".to_owned()));
+    drop(copy);
+
+    drop(store);
+    let active = temp_dir
+        .path()
+        .join(log_files(temp_dir.path()).last().unwrap());
+    append_to(&active, b"torn tail")?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.discarded_bytes(), 9);
+    let other = backup_dir.path().join("other");
+    reader.checkpoint(&other)?;
+    let copy = KvStore::open(&other)?;
+    assert_eq!(copy.discarded_bytes(), 0);
+    assert_eq!(copy.scan(..).keys().count(), 200);
+    Ok(())
+}
+
+// The writes go on while the compaction runs in the background, and every
+// write is visible right away.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let padding = "x".repeat(1000);
+    for iter in 0..5 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}{}", iter, padding);
+            store.set(key.clone(), value.clone())?;
+            assert_eq!(store.get(key)?, Some(value));
+        }
+    }
+    store.wait_for_compaction()?;
+    // Only the last round of the values is alive, most of the log is gone.
+    let len: u64 = log_files(temp_dir.path())
+        .iter()
+        .map(|file| std::fs::metadata(temp_dir.path().join(file)).unwrap().len())
+        .sum();
+    assert!(len < 4 * 1000 * 1024);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("4{}", padding))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn open_create_if_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    match KvStore::open_with(&path, Options::new().create_if_missing(false)) {
+        Err(KvsError::StoreNotFound) => (),
+        other => panic!("expect StoreNotFound, got {:?}", other.map(|_| ())),
+    }
+    assert!(!path.exists());
+
+    let store = KvStore::open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with(&path, Options::new().create_if_missing(false))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn open_error_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open_with(
+        temp_dir.path(),
+        Options::new().error_if_exists(true),
+    )?);
+    match KvStore::open_with(temp_dir.path(), Options::new().error_if_exists(true)) {
+        Err(KvsError::StoreExists) => Ok(()),
+        other => panic!("expect StoreExists, got {:?}", other.map(|_| ())),
+    }
+}
+
+// A store opened read only can be read, but nothing in the directory changes,
+// not even a torn tail is truncated.
+#[test]
+fn open_read_only_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let segment = temp_dir
+        .path()
+        .join(log_files(temp_dir.path()).pop().unwrap());
+    append_to(&segment, &[1, 2, 3])?;
+    let len = std::fs::metadata(&segment)?.len();
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(store.discarded_bytes(), 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for result in [
+        store.set("key2".to_owned(), "value2".to_owned()),
+        store.remove("key1".to_owned()),
+        store.compact(),
+    ] {
+        match result {
+            Err(KvsError::ReadOnly) => (),
+            other => panic!("expect ReadOnly, got {:?}", other),
+        }
+    }
+    drop(store);
+    assert_eq!(std::fs::metadata(&segment)?.len(), len);
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+
+    match KvStore::open_with(
+        temp_dir.path().join("missing"),
+        Options::new().read_only(true),
+    ) {
+        Err(KvsError::StoreNotFound) => Ok(()),
+        other => panic!("expect StoreNotFound, got {:?}", other.map(|_| ())),
+    }
+}
+
+// The files in the directory, and their lengths and modification times.
+fn dir_state(dir: &std::path::Path) -> Vec<(String, u64, std::time::SystemTime)> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .expect("fail to read directory")
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let metadata = entry.metadata().unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            (name, metadata.len(), metadata.modified().unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// `open_read_only` never changes the directory, even with a stale hint and
+// plenty to compact, and it never creates a missing store.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().segment_size(1024))?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    drop(store);
+    remove_hint(temp_dir.path())?;
+    let state = dir_state(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.scan(..).keys().count(), 100);
+    for result in [
+        store.set("key2".to_owned(), "value2".to_owned()),
+        store.remove("key1".to_owned()),
+        store.compact(),
+    ] {
+        match result {
+            Err(KvsError::ReadOnly) => (),
+            other => panic!("expect ReadOnly, got {:?}", other),
+        }
+    }
+    drop(store);
+    assert_eq!(dir_state(temp_dir.path()), state);
+
+    let missing = temp_dir.path().join("missing");
+    match KvStore::open_read_only(&missing) {
+        Err(KvsError::StoreNotFound) => (),
+        other => panic!("expect StoreNotFound, got {:?}", other.map(|_| ())),
+    }
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// `kvs --read-only` reads a store another process writes to, and rejects the
+// writes.
+#[test]
+fn cli_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--read-only"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1 value1").trim());
+    for args in [&["set", "key2", "value2"][..], &["rm", "key1"]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--read-only")
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("ReadOnly"));
+    }
+    drop(store);
+
+    let empty = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "get", "key1"])
+        .current_dir(&empty)
+        .assert()
+        .failure()
+        .stderr(contains("StoreNotFound"));
+    assert_eq!(dir_state(empty.path()), vec![]);
+    Ok(())
+}
+
+// Only one store writes to a directory at a time, in this process or another
+// one, but the stores opened read only are opened next to it.
+#[test]
+fn open_store_in_use() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreInUse) => (),
+        other => panic!("expect StoreInUse, got {:?}", other.map(|_| ())),
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("StoreInUse"));
+
+    let reader = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    let other_reader = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        other_reader.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn max_key_and_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        Options::new().max_key_size(4).max_value_size(6),
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match store.set("key10".to_owned(), "value1".to_owned()) {
+        Err(KvsError::KeyTooLarge { size: 5, max: 4 }) => (),
+        other => panic!("expect KeyTooLarge, got {:?}", other),
+    }
+    match store.set("key1".to_owned(), "value10".to_owned()) {
+        Err(KvsError::ValueTooLarge { size: 7, max: 6 }) => (),
+        other => panic!("expect ValueTooLarge, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A lower threshold compacts the log sooner.
+#[test]
+fn compaction_threshold_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        Options::new()
+            .compaction_threshold(1024)
+            .sync(SyncPolicy::Always),
+    )?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.wait_for_compaction()?;
+    let len: u64 = log_files(temp_dir.path())
+        .iter()
+        .map(|file| std::fs::metadata(temp_dir.path().join(file)).unwrap().len())
+        .sum();
+    assert!(len < 2048);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+/// A log file which counts the syncs, and makes them take a while so the
+/// concurrent writers pile up behind them.
+struct CountingFile {
+    file: std::fs::File,
+    syncs: Arc<AtomicUsize>,
+    delay: Duration,
+}
+
+impl LogFile for CountingFile {
+    fn append(&self, buf: &[u8]) -> std::io::Result<()> {
+        self.file.append(buf)
+    }
+
+    fn sync_data(&self) -> std::io::Result<()> {
+        thread::sleep(self.delay);
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.file.sync_data()
+    }
+}
+
+fn counting_options(sync: SyncPolicy, delay: Duration) -> (Options, Arc<AtomicUsize>) {
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let mut options = Options::new();
+    let counter = Arc::clone(&syncs);
+    options.sync(sync).log_file(move |file| {
+        Box::new(CountingFile {
+            file,
+            syncs: Arc::clone(&counter),
+            delay,
+        })
+    });
+    (options, syncs)
+}
+
+// The writes are never synced by default.
+#[test]
+fn sync_none() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, syncs) = counting_options(SyncPolicy::None, Duration::from_millis(0));
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    assert_eq!(syncs.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+// Each write is synced with `SyncPolicy::Always`.
+#[test]
+fn sync_always() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, syncs) = counting_options(SyncPolicy::Always, Duration::from_millis(0));
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(syncs.load(Ordering::SeqCst), 11);
+    Ok(())
+}
+
+// The writes are synced in the background with `SyncPolicy::Interval`, only
+// when there's something new to sync, and once more when the store is closed.
+#[test]
+fn sync_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, syncs) = counting_options(
+        SyncPolicy::Interval(Duration::from_millis(50)),
+        Duration::from_millis(0),
+    );
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(300));
+    let synced = syncs.load(Ordering::SeqCst);
+    assert!((1..100).contains(&synced), "{} syncs", synced);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(syncs.load(Ordering::SeqCst), synced);
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert_eq!(syncs.load(Ordering::SeqCst), synced + 1);
+    Ok(())
+}
+
+// A single writer is synced on each write with `SyncPolicy::GroupCommit`, but
+// the concurrent writers share the syncs.
+#[test]
+fn sync_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, syncs) = counting_options(SyncPolicy::GroupCommit, Duration::from_millis(5));
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    assert_eq!(syncs.load(Ordering::SeqCst), 10);
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for iter in 0..20 {
+                    store
+                        .set(format!("key{}_{}", thread_id, iter), "value".to_owned())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let synced = syncs.load(Ordering::SeqCst) - 10;
+    assert!((20..160).contains(&synced), "{} syncs", synced);
+    Ok(())
+}
+
+// The writes waiting for a batched sync are synced when the segment rolls over.
+#[test]
+fn sync_group_commit_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut options, syncs) = counting_options(SyncPolicy::GroupCommit, Duration::from_millis(0));
+    options.segment_size(64);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    assert_eq!(syncs.load(Ordering::SeqCst), 10);
+    assert!(log_files(temp_dir.path()).len() > 1);
+    Ok(())
+}
+
+// A batch is applied all together, and survives reopening the store.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .remove("key1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .set("key2".to_owned(), "value4".to_owned())
+        .remove("missing".to_owned());
+    store.write(batch)?;
+    store.write(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // The batch is split into single records by the compaction.
+    store.set("key3".to_owned(), "value5".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// A batch torn by a crash is skipped entirely.
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write(batch)?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&path)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Nothing in a batch is written if one of the values is too large.
+#[test]
+fn write_batch_too_large() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().max_value_size(6))?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .set("key2".to_owned(), "value10".to_owned());
+    match store.write(batch) {
+        Err(KvsError::ValueTooLarge { size: 7, max: 6 }) => (),
+        other => panic!("expect ValueTooLarge, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A segment written by an older format version is read, but the new records
+// go to a new segment.
+#[test]
+fn open_older_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let path = temp_dir.path().join("1.log");
+    let mut data = std::fs::read(&path)?;
+    data[4] = 1;
+    std::fs::write(&path, &data)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(std::fs::read(&path)?, data);
+    assert_eq!(log_files(temp_dir.path()), vec!["1.log", "2.log"]);
+    Ok(())
+}
+
+// A record of nested batches, which this crate never writes, is an error
+// rather than a stack overflow, however deep they're nested.
+#[test]
+fn open_nested_batch_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut payload = Vec::new();
+    for _ in 0..200_000 {
+        // `Operation::Batch` holding a single operation.
+        payload.extend_from_slice(&3u32.to_le_bytes());
+        payload.extend_from_slice(&1u64.to_le_bytes());
+    }
+    payload.extend_from_slice(&3u32.to_le_bytes());
+    payload.extend_from_slice(&0u64.to_le_bytes());
+    let mut data = b"KVS\0\x03\0\0\0".to_vec();
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    data.extend_from_slice(&payload);
+    std::fs::write(temp_dir.path().join("1.log"), data)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::InvalidRecord(_)) => (),
+        other => panic!("expect InvalidRecord, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Scan the keys in a range or with a prefix, in order.
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // More keys than a scan fetches at a time.
+    for user in 0..300 {
+        store.set(format!("user:{:03}:name", user), format!("name{}", user))?;
+        store.set(format!("user:{:03}:mail", user), format!("mail{}", user))?;
+    }
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:007:mail".to_owned())?;
+
+    let pairs: Vec<(String, String)> = store
+        .scan("user:005".to_owned().."user:008".to_owned())
+        .collect::<Result<_>>()?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(
+        keys,
+        vec![
+            "user:005:mail",
+            "user:005:name",
+            "user:006:mail",
+            "user:006:name",
+            "user:007:name"
+        ]
+    );
+    assert_eq!(pairs[0].1, "mail5");
+
+    let names: Vec<String> = store
+        .scan_prefix("user:".to_owned())
+        .keys()
+        .collect::<Result<Vec<String>>>()?
+        .into_iter()
+        .filter(|key| key.ends_with(":name"))
+        .collect();
+    assert_eq!(names.len(), 300);
+    assert_eq!(names[299], "user:299:name");
+
+    let all: Vec<(String, String)> = store.scan(..).collect::<Result<_>>()?;
+    assert_eq!(all.len(), 600);
+    assert_eq!(all[0], ("group:1".to_owned(), "admins".to_owned()));
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    assert_eq!(store.scan_prefix("none".to_owned()).count(), 0);
+    assert_eq!(store.scan("b".to_owned().."a".to_owned()).count(), 0);
+    assert_eq!(store.scan("a".to_owned().."a".to_owned()).count(), 0);
+    Ok(())
+}
+
+// A scan goes on while the keys are written and compacted.
+#[test]
+fn scan_while_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in 0..500 {
+        store.set(format!("key{:03}", key), "old".to_owned())?;
+    }
+    let mut scan = store.scan(..);
+    let (first, _) = scan.next().unwrap()?;
+    assert_eq!(first, "key000");
+    for key in 0..500 {
+        store.set(format!("key{:03}", key), "new".to_owned())?;
+    }
+    store.compact()?;
+    let rest: Vec<(String, String)> = scan.collect::<Result<_>>()?;
+    assert_eq!(rest.len(), 499);
+    assert_eq!(rest[498].0, "key499");
+    Ok(())
+}
+
+// An expired key is treated as absent, also after reopening the store.
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "session1".to_owned(),
+        "token1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "session2".to_owned(),
+        "token2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("session3".to_owned(), "token3".to_owned())?;
+    assert_eq!(store.get("session1".to_owned())?, Some("token1".to_owned()));
+
+    thread::sleep(Duration::from_millis(150));
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("session1".to_owned())?, None);
+        assert_eq!(store.get("session2".to_owned())?, Some("token2".to_owned()));
+        assert_eq!(store.get("session3".to_owned())?, Some("token3".to_owned()));
+        let keys: Vec<String> = store
+            .scan_prefix("session".to_owned())
+            .keys()
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec!["session2", "session3"]);
+        match store.remove("session1".to_owned()) {
+            Err(KvsError::KeyNotFound) => (),
+            other => panic!("expect KeyNotFound, got {:?}", other),
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // A set without a TTL makes the key permanent again.
+    store.set("session1".to_owned(), "token4".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session1".to_owned())?, Some("token4".to_owned()));
+
+    // A TTL too long to count in milliseconds never expires.
+    let ttl = Duration::from_millis(u64::MAX) + Duration::from_millis(1);
+    store.set_with_ttl("session5".to_owned(), "token5".to_owned(), ttl)?;
+    store.expire("session1".to_owned(), ttl)?;
+    assert_eq!(store.get("session5".to_owned())?, Some("token5".to_owned()));
+    assert_eq!(store.get("session1".to_owned())?, Some("token4".to_owned()));
+    Ok(())
+}
+
+// The compaction drops the expired keys.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for key in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key),
+            value.clone(),
+            Duration::from_millis(0),
+        )?;
+    }
+    store.set_with_ttl(
+        "live".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.compact()?;
+
+    let len: u64 = log_files(temp_dir.path())
+        .iter()
+        .map(|file| std::fs::metadata(temp_dir.path().join(file)).unwrap().len())
+        .sum();
+    assert!(len < 1024);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("live".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The binary keys are ordered by their bytes.
+#[test]
+fn scan_binary_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(vec![0x01, 0xff], vec![1])?;
+    store.set_bytes(vec![0x01, 0x00], vec![2])?;
+    store.set_bytes(vec![0x02], vec![3])?;
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> =
+        store.scan_prefix_bytes(vec![0x01]).collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![(vec![0x01, 0x00], vec![2]), (vec![0x01, 0xff], vec![1])]
+    );
+    let keys: Vec<Vec<u8>> = store.scan_bytes(vec![0x02]..).keys().collect();
+    assert_eq!(keys, vec![vec![0x02], b"text".to_vec()]);
+
+    match store.scan(..).nth(1) {
+        Some(Err(KvsError::InvalidUtf8(_))) => (),
+        other => panic!("expect InvalidUtf8, got {:?}", other),
+    }
+    let pairs: Vec<(String, String)> = store.scan("t".to_owned()..).collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![("text".to_owned(), "value".to_owned())]);
+    Ok(())
+}
+
+/// Kills the child process when dropped, so a failed test doesn't leave a
+/// server behind.
+struct ChildGuard(std::process::Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// An address nobody listens on, most likely.
+fn free_addr() -> std::net::SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn wait_for_server(addr: std::net::SocketAddr) {
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server at {} didn't start", addr);
+}
+
+// `kvs-client` should set, get and remove the keys of a `kvs-server`, which
+// stores them in its current directory.
+#[test]
+fn cli_client_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr().to_string();
+    let server = ChildGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr])
+            .current_dir(&temp_dir)
+            .stderr(std::process::Stdio::null())
+            .spawn()?,
+    );
+    wait_for_server(addr.parse().unwrap());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+    drop(server);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `kvs-client` should fail on an invalid address, or when nobody listens on it.
+#[test]
+fn cli_client_invalid_addr() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "invalid-addr"])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &free_addr().to_string()])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "invalid-addr"])
+        .assert()
+        .failure();
+}
+
+// A client sends many requests on a connection, with binary keys and values.
+#[test]
+fn client_server_requests() -> Result<()> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let store = MemStore::new();
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.set_bytes(vec![0x00, 0xff], vec![0xc3, 0x28])?;
+    assert_eq!(client.get_bytes(vec![0x00, 0xff])?, Some(vec![0xc3, 0x28]));
+    client.remove("key1".to_owned())?;
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        other => panic!("expect KeyNotFound, got {:?}", other),
+    }
+    client.set_bytes(b"key3".to_vec(), vec![0xff])?;
+    match client.get("key3".to_owned()) {
+        Err(KvsError::InvalidUtf8(_)) => (),
+        other => panic!("expect InvalidUtf8, got {:?}", other),
+    }
+
+    // Another client sees the same store, while the first one is connected.
+    let mut other = KvsClient::connect(addr)?;
+    assert_eq!(other.get_bytes(vec![0x00, 0xff])?, Some(vec![0xc3, 0x28]));
+    other.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(client.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A RESP2 value, as a Redis client sees it.
+#[derive(Debug, PartialEq)]
+enum Resp {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Resp>),
+}
+
+fn bulk(bytes: &str) -> Resp {
+    Resp::Bulk(Some(bytes.as_bytes().to_vec()))
+}
+
+// A hand-written RESP client, so the server is tested against the protocol
+// rather than against itself.
+struct RespClient {
+    reader: std::io::BufReader<std::net::TcpStream>,
+    writer: std::net::TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: std::net::SocketAddr) -> RespClient {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        RespClient {
+            reader: std::io::BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn encode(args: &[&str]) -> Vec<u8> {
+        let mut command = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            command.extend(format!("${}\r\n{}\r\n", arg.len(), arg).bytes());
+        }
+        command
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        use std::io::Write;
+        self.writer.write_all(bytes).unwrap();
+    }
+
+    fn command(&mut self, args: &[&str]) -> Resp {
+        self.send(&RespClient::encode(args));
+        self.read()
+    }
+
+    fn read(&mut self) -> Resp {
+        use std::io::{BufRead, Read};
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "bad line {:?}", line);
+        let (kind, rest) = line.trim_end().split_at(1);
+        match kind {
+            "+" => Resp::Status(rest.to_owned()),
+            "-" => Resp::Error(rest.to_owned()),
+            ":" => Resp::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Resp::Bulk(None),
+                len => {
+                    let mut bytes = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut bytes).unwrap();
+                    assert_eq!(bytes.split_off(len as usize), b"\r\n");
+                    Resp::Bulk(Some(bytes))
+                }
+            },
+            "*" => Resp::Array((0..rest.parse().unwrap()).map(|_| self.read()).collect()),
+            _ => panic!("bad reply {:?}", line),
+        }
+    }
+}
+
+fn spawn_resp_server(store: KvStore) -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    thread::spawn(move || RespServer::new(store, pool).serve(listener));
+    addr
+}
+
+// The RESP server should answer the commands like Redis.
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut client = RespClient::connect(spawn_resp_server(store.clone()));
+
+    assert_eq!(client.command(&["PING"]), Resp::Status("PONG".to_owned()));
+    assert_eq!(client.command(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(client.command(&["GET", "key1"]), Resp::Bulk(None));
+    assert_eq!(
+        client.command(&["SET", "key1", "value 1"]),
+        Resp::Status("OK".to_owned())
+    );
+    assert_eq!(client.command(&["GET", "key1"]), bulk("value 1"));
+    client.command(&["SET", "key2", ""]);
+    assert_eq!(client.command(&["GET", "key2"]), bulk(""));
+    assert_eq!(
+        client.command(&["EXISTS", "key1", "key2", "key3", "key1"]),
+        Resp::Integer(3)
+    );
+    assert_eq!(client.command(&["DEL", "key1", "key3"]), Resp::Integer(1));
+    assert_eq!(client.command(&["EXISTS", "key1"]), Resp::Integer(0));
+    assert_eq!(store.get("key2".to_owned())?, Some("".to_owned()));
+
+    // An inline command, and pipelined commands.
+    client.send(b"SET key3 value3\r\n");
+    assert_eq!(client.read(), Resp::Status("OK".to_owned()));
+    let mut pipeline = RespClient::encode(&["GET", "key3"]);
+    pipeline.extend(RespClient::encode(&["DEL", "key3"]));
+    client.send(&pipeline);
+    assert_eq!(client.read(), bulk("value3"));
+    assert_eq!(client.read(), Resp::Integer(1));
+    Ok(())
+}
+
+// `KEYS` and `SCAN` should return the matching keys in order.
+#[test]
+fn resp_keys_and_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..25 {
+        store.set(format!("user:{:02}", i), i.to_string())?;
+    }
+    store.set("group:1".to_owned(), "1".to_owned())?;
+    store.set("a".repeat(100), "a".to_owned())?;
+    let addr = spawn_resp_server(store);
+    let mut client = RespClient::connect(addr);
+
+    assert_eq!(
+        client.command(&["KEYS", "user:1?"]),
+        Resp::Array((10..20).map(|i| bulk(&format!("user:{}", i))).collect())
+    );
+    assert_eq!(
+        client.command(&["KEYS", "*:[0-1]1"]),
+        Resp::Array(vec![bulk("user:01"), bulk("user:11")])
+    );
+    assert_eq!(
+        client.command(&["KEYS", "user:[^0-1]*"]),
+        Resp::Array((20..25).map(|i| bulk(&format!("user:{}", i))).collect())
+    );
+    assert_eq!(client.command(&["KEYS", "nothing*"]), Resp::Array(vec![]));
+    // Backtracking over every `*` would take forever.
+    let pattern = "*a".repeat(40);
+    assert_eq!(
+        client.command(&["KEYS", &format!("{}b", pattern)]),
+        Resp::Array(vec![])
+    );
+    assert_eq!(
+        client.command(&["KEYS", &pattern]),
+        Resp::Array(vec![bulk(&"a".repeat(100))])
+    );
+    match client.command(&["KEYS", "*"]) {
+        Resp::Array(keys) => assert_eq!(keys[1], bulk("group:1")),
+        reply => panic!("bad KEYS reply {:?}", reply),
+    }
+
+    // The scan goes on with the cursor on any connection.
+    let mut clients = [client, RespClient::connect(addr)];
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    for round in 0.. {
+        let reply = clients[round % 2].command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "7"]);
+        let mut reply = match reply {
+            Resp::Array(reply) => reply,
+            reply => panic!("bad SCAN reply {:?}", reply),
+        };
+        match reply.pop() {
+            Some(Resp::Array(batch)) => keys.extend(batch),
+            reply => panic!("bad SCAN reply {:?}", reply),
+        }
+        cursor = match reply.pop() {
+            Some(Resp::Bulk(Some(cursor))) => String::from_utf8(cursor)?,
+            reply => panic!("bad SCAN reply {:?}", reply),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(
+        keys,
+        (0..25)
+            .map(|i| bulk(&format!("user:{:02}", i)))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        clients[0].command(&["SCAN", "12345"]),
+        Resp::Error("ERR invalid cursor".to_owned())
+    );
+    Ok(())
+}
+
+// `EXPIRE` and `SET ... EX` should expire the keys.
+#[test]
+fn resp_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut client = RespClient::connect(spawn_resp_server(store.clone()));
+
+    client.command(&["SET", "key1", "value1"]);
+    client.command(&["SET", "key2", "value2", "PX", "300"]);
+    client.command(&["SET", "key3", "value3"]);
+    assert_eq!(client.command(&["EXPIRE", "key1", "1"]), Resp::Integer(1));
+    assert_eq!(client.command(&["EXPIRE", "key3", "0"]), Resp::Integer(1));
+    assert_eq!(client.command(&["EXPIRE", "key4", "1"]), Resp::Integer(0));
+    assert_eq!(client.command(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(client.command(&["GET", "key3"]), Resp::Bulk(None));
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.command(&["GET", "key1"]), Resp::Bulk(None));
+    assert_eq!(client.command(&["GET", "key2"]), Resp::Bulk(None));
+    assert_eq!(client.command(&["EXPIRE", "key1", "1"]), Resp::Integer(0));
+
+    // The expiry survives reopening the store. The server still writes to it,
+    // so it's opened read only.
+    client.command(&["SET", "key5", "value5"]);
+    client.command(&["EXPIRE", "key5", "100"]);
+    drop(client);
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// The RESP server should answer the bad commands with errors, and close the
+// connection on a protocol error.
+#[test]
+fn resp_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().max_value_size(4))?;
+    let mut client = RespClient::connect(spawn_resp_server(store));
+
+    let error = |message: &str| Resp::Error(message.to_owned());
+    assert_eq!(
+        client.command(&["FLUSHALL"]),
+        error("ERR unknown command 'flushall'")
+    );
+    assert_eq!(
+        client.command(&["GET"]),
+        error("ERR wrong number of arguments for 'get' command")
+    );
+    assert_eq!(
+        client.command(&["SET", "key1", "v", "EX"]),
+        error("ERR syntax error")
+    );
+    assert_eq!(
+        client.command(&["SET", "key1", "v", "EX", "0"]),
+        error("ERR invalid expire time in 'set' command")
+    );
+    assert_eq!(
+        client.command(&["EXPIRE", "key1", "soon"]),
+        error("ERR value is not an integer or out of range")
+    );
+    assert_eq!(
+        client.command(&["SET", "key1", "too large"]),
+        error("ERR Value too large: 9 bytes, at most 4")
+    );
+    assert_eq!(client.command(&["PING"]), Resp::Status("PONG".to_owned()));
+
+    client.send(b"*1\r\n$-5\r\n");
+    assert_eq!(
+        client.read(),
+        error("ERR Protocol error: invalid bulk length")
+    );
+    let mut rest = Vec::new();
+    std::io::Read::read_to_end(&mut client.reader, &mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+// `kvs-server --protocol resp` should serve the store in its current directory
+// to Redis clients.
+#[test]
+fn cli_resp_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let server = ChildGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr.to_string(), "--protocol", "resp"])
+            .current_dir(&temp_dir)
+            .stderr(std::process::Stdio::null())
+            .spawn()?,
+    );
+    wait_for_server(addr);
+
+    let mut client = RespClient::connect(addr);
+    assert_eq!(
+        client.command(&["SET", "key1", "value1"]),
+        Resp::Status("OK".to_owned())
+    );
+    assert_eq!(client.command(&["GET", "key1"]), bulk("value1"));
+    drop(client);
+    drop(server);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `AsyncKvStore` should do what `KvStore` does.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::new(KvStore::open(temp_dir.path())?);
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set_bytes(b"key2".to_vec(), vec![0xff]).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_bytes(b"key2".to_vec()).await?, Some(vec![0xff]));
+    assert_eq!(store.get("key3".to_owned()).await?, None);
+    store.remove("key1".to_owned()).await?;
+    match store.remove("key1".to_owned()).await {
+        Err(KvsError::KeyNotFound) => (),
+        other => panic!("expect KeyNotFound, got {:?}", other),
+    }
+
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    store.write(batch).await?;
+    store.compact().await?;
+    assert_eq!(
+        store.store().get("key4".to_owned())?,
+        Some("value4".to_owned())
+    );
+
+    // Many operations in flight at once.
+    let sets: Vec<_> = (0..100)
+        .map(|i| store.set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    for set in sets {
+        set.await?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// The disk work shouldn't block the executor, even on a single thread.
+#[tokio::test(flavor = "current_thread")]
+async fn async_does_not_block_executor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (options, syncs) = counting_options(SyncPolicy::Always, Duration::from_millis(300));
+    let store = AsyncKvStore::new(KvStore::open_with(temp_dir.path(), &options)?);
+
+    let mut set = store.set("key1".to_owned(), "value1".to_owned());
+    tokio::select! {
+        biased;
+        _ = &mut set => panic!("the set finished before the timer"),
+        _ = tokio::time::sleep(Duration::from_millis(10)) => (),
+    }
+    set.await?;
+    assert_eq!(syncs.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+// Dropping a future shouldn't cancel its operation: it's applied just like an
+// awaited one.
+#[tokio::test(flavor = "current_thread")]
+async fn async_dropped_future_is_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::with_threads(KvStore::open(temp_dir.path())?, 1);
+
+    // Lost a `select!`.
+    tokio::select! {
+        biased;
+        _ = async {} => (),
+        _ = store.set("key1".to_owned(), "value1".to_owned()) => panic!("the set won"),
+    }
+    // Never polled.
+    drop(store.set("key2".to_owned(), "value2".to_owned()));
+    // Timed out.
+    let remove = store.remove("key1".to_owned());
+    let _ = tokio::time::timeout(Duration::from_nanos(1), remove).await;
+
+    // A single thread runs them in order.
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// Dropping the last `AsyncKvStore` should wait for the operations already
+// called.
+#[test]
+fn async_drop_waits_for_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::with_threads(KvStore::open(temp_dir.path())?, 4);
+    for i in 0..100 {
+        drop(store.set(format!("key{}", i), format!("value{}", i)));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A pool should run all the jobs, on several threads at once.
+fn thread_pool_runs_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let barrier = Arc::new(std::sync::Barrier::new(4));
+    let done = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for _ in 0..4 {
+        let (barrier, sender) = (Arc::clone(&barrier), sender.clone());
+        pool.spawn(move || {
+            // Deadlocks unless the 4 jobs run at once.
+            barrier.wait();
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    for _ in 0..100 {
+        let (done, sender) = (Arc::clone(&done), sender.clone());
+        pool.spawn(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..100 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    assert_eq!(done.load(Ordering::SeqCst), 100);
+    Ok(())
+}
+
+// A pool should go on running jobs after some of them panic.
+fn thread_pool_survives_panics<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    for _ in 0..10 {
+        pool.spawn(|| panic!("the job panics on purpose"));
+    }
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for _ in 0..10 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(()).unwrap());
+    }
+    for _ in 0..10 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool() -> Result<()> {
+    thread_pool_runs_jobs::<NaiveThreadPool>()?;
+    thread_pool_survives_panics::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool() -> Result<()> {
+    thread_pool_runs_jobs::<SharedQueueThreadPool>()?;
+    thread_pool_survives_panics::<SharedQueueThreadPool>()?;
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool() -> Result<()> {
+    thread_pool_runs_jobs::<RayonThreadPool>()?;
+    thread_pool_survives_panics::<RayonThreadPool>()
+}
+
+// Dropping a `SharedQueueThreadPool` should wait for the jobs already spawned.
+#[test]
+fn shared_queue_thread_pool_drop_waits() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let done = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let done = Arc::clone(&done);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    drop(pool);
+    assert_eq!(done.load(Ordering::SeqCst), 10);
+    Ok(())
+}
+
+// `kvs-server` should serve many clients at once with every pool.
+#[test]
+fn cli_server_pools() -> Result<()> {
+    for pool in &["naive", "shared-queue", "rayon"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr = free_addr();
+        let _server = ChildGuard(
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args([
+                    "--addr",
+                    &addr.to_string(),
+                    "--pool",
+                    pool,
+                    "--threads",
+                    "4",
+                ])
+                .current_dir(&temp_dir)
+                .stderr(std::process::Stdio::null())
+                .spawn()?,
+        );
+        wait_for_server(addr);
+
+        // All the clients are connected at once.
+        let mut clients = (0..4)
+            .map(|_| KvsClient::connect(addr))
+            .collect::<Result<Vec<_>>>()?;
+        let handles: Vec<_> = clients
+            .drain(..)
+            .enumerate()
+            .map(|(i, mut client)| {
+                thread::spawn(move || -> Result<()> {
+                    for j in 0..20 {
+                        let key = format!("key{}-{}", i, j);
+                        client.set(key.clone(), format!("value{}", j))?;
+                        assert_eq!(client.get(key)?, Some(format!("value{}", j)));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+    }
+
+    // By default, the idle clients holding their connections don't starve the
+    // others, however few CPUs there are.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let _server = ChildGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr.to_string(), "--threads", "1"])
+            .current_dir(&temp_dir)
+            .stderr(std::process::Stdio::null())
+            .spawn()?,
+    );
+    wait_for_server(addr);
+    let mut idle = (0..2)
+        .map(|_| KvsClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    idle[0].set("key".to_owned(), "value".to_owned())?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let value = KvsClient::connect(addr).and_then(|mut client| client.get("key".to_owned()));
+        sender.send(value).unwrap();
+    });
+    let value = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("the client is never served")?;
+    assert_eq!(value, Some("value".to_owned()));
+    drop(idle);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--pool", "unknown"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// An operation of the model-based tests. The keys come from a small set, so
+// they're often overwritten and removed.
+#[derive(Debug, Clone)]
+enum ModelOp {
+    Set(Vec<u8>, Vec<u8>),
+    Get(Vec<u8>),
+    Remove(Vec<u8>),
+    Batch(Vec<(Vec<u8>, Option<Vec<u8>>)>),
+    Reopen,
+    Compact,
+}
+
+fn model_key() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        (0..8u8).prop_map(|i| format!("key{}", i).into_bytes()),
+        prop::collection::vec(any::<u8>(), 0..3),
+    ]
+}
+
+fn model_op() -> impl Strategy<Value = ModelOp> {
+    let value = prop::collection::vec(any::<u8>(), 0..64);
+    prop_oneof![
+        4 => (model_key(), value.clone()).prop_map(|(key, value)| ModelOp::Set(key, value)),
+        3 => model_key().prop_map(ModelOp::Get),
+        2 => model_key().prop_map(ModelOp::Remove),
+        1 => prop::collection::vec((model_key(), prop::option::of(value)), 0..6)
+            .prop_map(ModelOp::Batch),
+        1 => Just(ModelOp::Reopen),
+        1 => Just(ModelOp::Compact),
+    ]
+}
+
+// Apply the operations to a `KvStore` and to `MemStore` as a model, and check
+// they give the same results, and hold the same keys after every step.
+fn check_against_model(ops: Vec<ModelOp>) -> std::result::Result<(), TestCaseError> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Small segments and threshold, so the segments roll and the background
+    // compactions run all the time.
+    let mut options = Options::new();
+    options.segment_size(512).compaction_threshold(1024);
+    let open = || KvStore::open_with(temp_dir.path(), &options).unwrap();
+    let mut store = open();
+    let model = MemStore::new();
+    let mut keys = std::collections::BTreeSet::new();
+
+    for op in ops {
+        match op {
+            ModelOp::Set(key, value) => {
+                keys.insert(key.clone());
+                store.set_bytes(key.clone(), value.clone()).unwrap();
+                model.set_bytes(key, value).unwrap();
+            }
+            ModelOp::Get(key) => {
+                prop_assert_eq!(
+                    store.get_bytes(&key).unwrap(),
+                    model.get_bytes(&key).unwrap()
+                );
+            }
+            ModelOp::Remove(key) => {
+                let result = store.remove_bytes(&key);
+                match model.remove_bytes(&key) {
+                    Ok(()) => prop_assert!(result.is_ok(), "{:?}", result),
+                    Err(_) => {
+                        prop_assert!(matches!(result, Err(KvsError::KeyNotFound)), "{:?}", result)
+                    }
+                }
+            }
+            ModelOp::Batch(ops) => {
+                let mut batch = WriteBatch::new();
+                for (key, value) in ops {
+                    keys.insert(key.clone());
+                    match value {
+                        Some(value) => {
+                            batch.set_bytes(key.clone(), value.clone());
+                            model.set_bytes(key, value).unwrap();
+                        }
+                        None => {
+                            batch.remove_bytes(key.clone());
+                            let _ = model.remove_bytes(&key);
+                        }
+                    }
+                }
+                store.write(batch).unwrap();
+            }
+            ModelOp::Reopen => {
+                drop(store);
+                store = open();
+                prop_assert_eq!(store.discarded_bytes(), 0);
+            }
+            ModelOp::Compact => store.compact().unwrap(),
+        }
+
+        let mut expected = Vec::new();
+        for key in &keys {
+            let value = model.get_bytes(key).unwrap();
+            prop_assert_eq!(&store.get_bytes(key).unwrap(), &value, "key {:?}", key);
+            if let Some(value) = value {
+                expected.push((key.clone(), value));
+            }
+        }
+        let pairs = store.scan_bytes(..).collect::<Result<Vec<_>>>().unwrap();
+        prop_assert_eq!(pairs, expected);
+    }
+
+    // Everything is still there once the directory is reopened.
+    drop(store);
+    let store = open();
+    for key in &keys {
+        prop_assert_eq!(store.get_bytes(key).unwrap(), model.get_bytes(key).unwrap());
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn model_based(ops in prop::collection::vec(model_op(), 1..64)) {
+        check_against_model(ops)?;
+    }
+}